@@ -0,0 +1,261 @@
+//! One access log record per request, written once the response body is sent.
+//!
+//! Each request gets an id, taken from its `X-Request-Id` header or generated, and echoed in the
+//! `X-Request-Id` header of the response. Records are written as `key=value` text or as JSON.
+
+use crate::{
+    auth::ApiKey,
+    shutdown::{self, InFlight},
+    timing::SharedTimings,
+};
+use hyper::{
+    body::{Bytes, HttpBody, SizeHint},
+    header::HeaderValue,
+    http::Method,
+    Body, HeaderMap, Request, Response,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// Header carrying the id of a request.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// longest request id taken from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// format of the access log, set at startup
+pub(crate) static ACCESS_LOG_FORMAT: OnceCell<AccessLogFormat> = OnceCell::new();
+
+/// Format of the access log records.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessLogFormat {
+    /// `key=value` pairs.
+    #[default]
+    Text,
+    /// A JSON object.
+    Json,
+}
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessLogFormat::Text => write!(f, "text"),
+            AccessLogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The id of a request, stored in its extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+impl RequestId {
+    /// The `X-Request-Id` header of the request if it is a short printable string, or a new UUID.
+    pub(crate) fn of(req: &Request<Body>) -> Self {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LEN
+                    && id.bytes().all(|b| b.is_ascii_graphic())
+            });
+
+        match request_id {
+            Some(id) => RequestId(id.to_string()),
+            None => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+/// The voice and input length of a speech request, set in the response extensions by the speech
+/// handlers.
+#[derive(Debug, Clone)]
+pub(crate) struct SpeechInfo {
+    pub(crate) voice: String,
+    pub(crate) chars: usize,
+    /// Filled in until the audio is sent, for streamed responses.
+    pub(crate) timings: SharedTimings,
+}
+
+/// What the access log records of a request, collected while it is handled.
+#[derive(Debug)]
+pub(crate) struct AccessRecord {
+    request_id: String,
+    remote_addr: Option<SocketAddr>,
+    method: Method,
+    path: String,
+    start: Instant,
+    status: u16,
+    speech: Option<SpeechInfo>,
+    key_name: Option<String>,
+    // the request is in flight until its record is written
+    _in_flight: InFlight,
+}
+impl AccessRecord {
+    /// Start the record of a request, with the id in its extensions.
+    pub(crate) fn start(req: &Request<Body>) -> Self {
+        let request_id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_default();
+        let in_flight = shutdown::shutdown().track(format!(
+            "{} {} request_id={}",
+            req.method(),
+            req.uri().path(),
+            request_id
+        ));
+
+        AccessRecord {
+            request_id,
+            remote_addr: req.extensions().get::<SocketAddr>().copied(),
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            start: Instant::now(),
+            status: 0,
+            speech: None,
+            key_name: None,
+            _in_flight: in_flight,
+        }
+    }
+
+    /// Complete the record with the response, echo the request id, and write the record once the
+    /// response body is sent.
+    pub(crate) fn finish(mut self, mut response: Response<Body>) -> Response<LoggedBody> {
+        self.status = response.status().as_u16();
+        self.speech = response.extensions_mut().remove::<SpeechInfo>();
+        self.key_name = response
+            .extensions()
+            .get::<&'static ApiKey>()
+            .map(|api_key| api_key.name.clone());
+
+        if let Ok(request_id) = HeaderValue::from_str(&self.request_id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+        }
+
+        response.map(|body| LoggedBody {
+            inner: body,
+            bytes: 0,
+            record: Some(self),
+        })
+    }
+
+    fn write(&self, bytes: u64) {
+        let latency_ms = (self.start.elapsed().as_secs_f64() * 1e6).round() / 1e3;
+        let record = match ACCESS_LOG_FORMAT.get().copied().unwrap_or_default() {
+            AccessLogFormat::Text => {
+                let mut record = format!(
+                    "request_id={} remote_addr={} method={} path={} status={} latency_ms={} bytes={}",
+                    self.request_id,
+                    self.remote_addr
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    self.method,
+                    self.path,
+                    self.status,
+                    latency_ms,
+                    bytes
+                );
+                if let Some(speech) = self.speech.as_ref() {
+                    record.push_str(&format!(
+                        " voice={} chars={}",
+                        quote(&speech.voice),
+                        speech.chars
+                    ));
+                    let timings = speech.timings.get().to_text();
+                    if !timings.is_empty() {
+                        record.push(' ');
+                        record.push_str(&timings);
+                    }
+                }
+                if let Some(key_name) = self.key_name.as_ref() {
+                    record.push_str(&format!(" key={}", quote(key_name)));
+                }
+                record
+            }
+            AccessLogFormat::Json => serde_json::json!({
+                "request_id": &self.request_id,
+                "remote_addr": self.remote_addr.map(|addr| addr.to_string()),
+                "method": self.method.as_str(),
+                "path": &self.path,
+                "status": self.status,
+                "latency_ms": latency_ms,
+                "bytes": bytes,
+                "voice": self.speech.as_ref().map(|speech| &speech.voice),
+                "chars": self.speech.as_ref().map(|speech| speech.chars),
+                "key": &self.key_name,
+                "timings": self.speech.as_ref().map(|speech| speech.timings.get().to_json()),
+            })
+            .to_string(),
+        };
+
+        match self.status < 400 {
+            true => info!(target: "stdout", "{}", record),
+            false => error!(target: "stdout", "{}", record),
+        }
+    }
+}
+
+// quote a value with spaces or quotes, so that the text record stays parseable
+fn quote(value: &str) -> String {
+    match value.contains([' ', '"', '=']) || value.is_empty() {
+        true => format!("{:?}", value),
+        false => value.to_string(),
+    }
+}
+
+/// A response body that writes the access log record of its request when it is dropped, that is
+/// once it is sent or the client went away.
+pub(crate) struct LoggedBody {
+    inner: Body,
+    bytes: u64,
+    record: Option<AccessRecord>,
+}
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            this.bytes += data.len() as u64;
+        }
+
+        poll
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            record.write(self.bytes);
+        }
+    }
+}
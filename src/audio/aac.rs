@@ -0,0 +1,76 @@
+use super::{AudioError, AudioFormat, Pcm};
+use fdk_aac::enc::{BitRate, ChannelMode, Encoder, EncoderParams, Transport};
+
+// number of samples per channel in one AAC-LC frame
+const FRAME_SIZE: usize = 1024;
+
+pub(super) fn encode(pcm: &Pcm) -> Result<Vec<u8>, AudioError> {
+    let err = |msg: String| AudioError::Encode(AudioFormat::Aac, msg);
+
+    let channels = match pcm.channels {
+        1 => ChannelMode::Mono,
+        2 => ChannelMode::Stereo,
+        n => return Err(err(format!("unsupported channel count: {}", n))),
+    };
+    let encoder = Encoder::new(EncoderParams {
+        bit_rate: BitRate::VbrMedium,
+        sample_rate: pcm.sample_rate,
+        transport: Transport::Adts,
+        channels,
+    })
+    .map_err(|e| err(e.to_string()))?;
+
+    // pad the tail with one frame of silence so the encoder delay does not swallow the last words
+    let frame_len = FRAME_SIZE * pcm.channels as usize;
+    let mut samples = pcm.samples.clone();
+    let padded_len = (samples.len() / frame_len + 2) * frame_len;
+    samples.resize(padded_len, 0);
+
+    let mut buf = Vec::new();
+    let mut out = vec![0u8; 2048 * pcm.channels as usize];
+    let mut pos = 0;
+    while pos < samples.len() {
+        let info = encoder
+            .encode(&samples[pos..], &mut out)
+            .map_err(|e| err(e.to_string()))?;
+        if info.input_consumed == 0 && info.output_size == 0 {
+            break;
+        }
+        buf.extend_from_slice(&out[..info.output_size]);
+        pos += info.input_consumed;
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_writes_adts_frames() {
+        let pcm = Pcm {
+            samples: (0..24000)
+                .map(|i| ((i % 50) * 400 - 10000) as i16)
+                .collect(),
+            sample_rate: 24000,
+            channels: 1,
+        };
+        let buf = encode(&pcm).unwrap();
+
+        // every ADTS frame starts with a 12-bit sync word and carries its own length
+        let mut pos = 0;
+        let mut frames = 0;
+        while pos < buf.len() {
+            assert_eq!(buf[pos], 0xff);
+            assert_eq!(buf[pos + 1] & 0xf0, 0xf0);
+            let len = ((buf[pos + 3] as usize & 0x03) << 11)
+                | (buf[pos + 4] as usize) << 3
+                | (buf[pos + 5] as usize) >> 5;
+            pos += len;
+            frames += 1;
+        }
+        assert_eq!(pos, buf.len());
+        assert!(frames >= 24000 / FRAME_SIZE);
+    }
+}
@@ -0,0 +1,60 @@
+use super::{AudioError, AudioFormat, Pcm};
+use flacenc::{component::BitRepr, error::Verify};
+
+pub(super) fn encode(pcm: &Pcm) -> Result<Vec<u8>, AudioError> {
+    let err = |msg: String| AudioError::Encode(AudioFormat::Flac, msg);
+
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, e)| err(e.to_string()))?;
+
+    let samples: Vec<i32> = pcm.samples.iter().map(|&s| s as i32).collect();
+    let source = flacenc::source::MemSource::from_samples(
+        &samples,
+        pcm.channels as usize,
+        16,
+        pcm.sample_rate as usize,
+    );
+    // `EncodeError` only implements `Debug`
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| err(format!("{:?}", e)))?;
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream.write(&mut sink).map_err(|e| err(e.to_string()))?;
+
+    Ok(sink.as_slice().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_writes_stream_info() {
+        let pcm = Pcm {
+            samples: (0..4800)
+                .map(|i| ((i % 100) * 300 - 15000) as i16)
+                .collect(),
+            sample_rate: 22050,
+            channels: 2,
+        };
+        let buf = encode(&pcm).unwrap();
+
+        assert_eq!(&buf[0..4], b"fLaC");
+        // the first metadata block is STREAMINFO, 34 bytes long
+        assert_eq!(buf[4] & 0x7f, 0);
+        assert_eq!(&buf[5..8], &[0, 0, 34]);
+        let info = &buf[8..42];
+        let sample_rate = (info[10] as u32) << 12 | (info[11] as u32) << 4 | (info[12] as u32) >> 4;
+        let channels = ((info[12] >> 1) & 0x07) + 1;
+        let bits_per_sample = ((info[12] & 0x01) << 4 | info[13] >> 4) + 1;
+        let total_samples = ((info[13] & 0x0f) as u64) << 32
+            | u32::from_be_bytes([info[14], info[15], info[16], info[17]]) as u64;
+        assert_eq!(sample_rate, 22050);
+        assert_eq!(channels, 2);
+        assert_eq!(bits_per_sample, 16);
+        assert_eq!(total_samples, 2400);
+        // frames follow the metadata
+        assert!(buf.len() > 42);
+    }
+}
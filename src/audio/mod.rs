@@ -0,0 +1,283 @@
+//! Audio containers and encoders used to serve `response_format` on the speech endpoints.
+
+#[cfg(feature = "aac")]
+mod aac;
+#[cfg(feature = "flac")]
+mod flac;
+#[cfg(feature = "mp3")]
+mod mp3;
+#[cfg(feature = "opus")]
+mod opus;
+pub(crate) mod wav;
+
+use thiserror::Error;
+
+/// Sample rate of the `pcm` response format, which OpenAI fixes at 24kHz.
+pub(crate) const PCM_SAMPLE_RATE: u32 = 24000;
+
+/// Interleaved 16-bit signed audio samples.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pcm {
+    pub(crate) samples: Vec<i16>,
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u16,
+}
+impl Pcm {
+    /// Resample to `sample_rate` with linear interpolation.
+    pub(crate) fn resample(&self, sample_rate: u32) -> Pcm {
+        if sample_rate == self.sample_rate || self.samples.is_empty() || self.channels == 0 {
+            return Pcm {
+                samples: self.samples.clone(),
+                sample_rate,
+                channels: self.channels,
+            };
+        }
+
+        let channels = self.channels as usize;
+        let frames = self.samples.len() / channels;
+        let out_frames =
+            (frames as u64 * sample_rate as u64 / self.sample_rate as u64).max(1) as usize;
+        let step = self.sample_rate as f64 / sample_rate as f64;
+
+        let mut samples = Vec::with_capacity(out_frames * channels);
+        for i in 0..out_frames {
+            let pos = i as f64 * step;
+            let idx = (pos as usize).min(frames - 1);
+            let next = (idx + 1).min(frames - 1);
+            let frac = pos - idx as f64;
+            for ch in 0..channels {
+                let a = self.samples[idx * channels + ch] as f64;
+                let b = self.samples[next * channels + ch] as f64;
+                samples.push((a + (b - a) * frac).round() as i16);
+            }
+        }
+
+        Pcm {
+            samples,
+            sample_rate,
+            channels: self.channels,
+        }
+    }
+
+    /// Duration in seconds.
+    pub(crate) fn duration(&self) -> f64 {
+        match self.sample_rate == 0 || self.channels == 0 {
+            true => 0.0,
+            false => self.samples.len() as f64 / (self.sample_rate as f64 * self.channels as f64),
+        }
+    }
+
+    /// Raw little-endian bytes of the samples.
+    pub(crate) fn to_le_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.samples.len() * 2);
+        for sample in self.samples.iter() {
+            buf.extend_from_slice(&sample.to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// Output formats accepted in the `response_format` field of a speech request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum AudioFormat {
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+    Pcm,
+}
+impl AudioFormat {
+    pub(crate) const ALL: [AudioFormat; 6] = [
+        AudioFormat::Mp3,
+        AudioFormat::Opus,
+        AudioFormat::Aac,
+        AudioFormat::Flac,
+        AudioFormat::Wav,
+        AudioFormat::Pcm,
+    ];
+
+    /// Whether the encoder for this format was compiled into the server.
+    pub(crate) fn is_enabled(&self) -> bool {
+        match self {
+            AudioFormat::Mp3 => cfg!(feature = "mp3"),
+            AudioFormat::Opus => cfg!(feature = "opus"),
+            AudioFormat::Aac => cfg!(feature = "aac"),
+            AudioFormat::Flac => cfg!(feature = "flac"),
+            AudioFormat::Wav | AudioFormat::Pcm => true,
+        }
+    }
+
+    /// Formats that can be served by this build.
+    pub(crate) fn enabled() -> Vec<AudioFormat> {
+        Self::ALL.into_iter().filter(|f| f.is_enabled()).collect()
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Opus => "audio/ogg",
+            AudioFormat::Aac => "audio/aac",
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Pcm => "audio/pcm",
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Aac => "aac",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Pcm => "pcm",
+        }
+    }
+}
+impl Default for AudioFormat {
+    /// OpenAI defaults to `mp3` when `response_format` is omitted.
+    fn default() -> Self {
+        AudioFormat::Mp3
+    }
+}
+impl std::fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+impl std::str::FromStr for AudioFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp3" => Ok(AudioFormat::Mp3),
+            "opus" => Ok(AudioFormat::Opus),
+            "aac" => Ok(AudioFormat::Aac),
+            "flac" => Ok(AudioFormat::Flac),
+            "wav" => Ok(AudioFormat::Wav),
+            "pcm" => Ok(AudioFormat::Pcm),
+            _ => Err(format!("Invalid audio format: {}", s)),
+        }
+    }
+}
+
+/// Encode `pcm` into the container and codec of `format`.
+pub(crate) fn encode(pcm: &Pcm, format: AudioFormat) -> Result<Vec<u8>, AudioError> {
+    match format {
+        AudioFormat::Wav => Ok(wav::encode(pcm)),
+        AudioFormat::Pcm => Ok(pcm.resample(PCM_SAMPLE_RATE).to_le_bytes()),
+        #[cfg(feature = "mp3")]
+        AudioFormat::Mp3 => mp3::encode(pcm),
+        #[cfg(feature = "opus")]
+        AudioFormat::Opus => opus::encode(pcm),
+        #[cfg(feature = "aac")]
+        AudioFormat::Aac => aac::encode(pcm),
+        #[cfg(feature = "flac")]
+        AudioFormat::Flac => flac::encode(pcm),
+        #[allow(unreachable_patterns)]
+        format => Err(AudioError::Disabled(format)),
+    }
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AudioError {
+    /// The input audio could not be parsed
+    #[cfg_attr(not(any(feature = "piper", feature = "gpt_sovits")), allow(dead_code))]
+    #[error("Failed to decode audio: {0}")]
+    Decode(String),
+    /// The encoder rejected the audio or its parameters
+    #[allow(dead_code)]
+    #[error("Failed to encode audio as {0}: {1}")]
+    Encode(AudioFormat, String),
+    /// The encoder for the format is not compiled into this build
+    #[error("The `{0}` response format is not enabled in this build")]
+    Disabled(AudioFormat),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample_interpolates_linearly() {
+        let pcm = Pcm {
+            samples: vec![0, 100, 200, 300],
+            sample_rate: 8000,
+            channels: 1,
+        };
+
+        let up = pcm.resample(16000);
+        assert_eq!(up.sample_rate, 16000);
+        assert_eq!(up.samples, vec![0, 50, 100, 150, 200, 250, 300, 300]);
+
+        let down = pcm.resample(4000);
+        assert_eq!(down.samples, vec![0, 200]);
+    }
+
+    #[test]
+    fn resample_keeps_channels_apart() {
+        let pcm = Pcm {
+            samples: vec![0, -1000, 1000, -2000],
+            sample_rate: 8000,
+            channels: 2,
+        };
+
+        let up = pcm.resample(16000);
+        assert_eq!(up.channels, 2);
+        assert_eq!(
+            up.samples,
+            vec![0, -1000, 500, -1500, 1000, -2000, 1000, -2000]
+        );
+    }
+
+    #[test]
+    fn resample_to_the_same_rate_copies() {
+        let pcm = Pcm {
+            samples: vec![1, 2, 3],
+            sample_rate: 24000,
+            channels: 1,
+        };
+        assert_eq!(pcm.resample(24000).samples, pcm.samples);
+        assert!(Pcm::default().resample(24000).samples.is_empty());
+    }
+
+    #[test]
+    fn duration_counts_frames() {
+        let pcm = Pcm {
+            samples: vec![0; 48000],
+            sample_rate: 24000,
+            channels: 2,
+        };
+        assert_eq!(pcm.duration(), 1.0);
+        assert_eq!(Pcm::default().duration(), 0.0);
+    }
+
+    #[test]
+    fn encode_pcm_is_24khz_little_endian() {
+        let pcm = Pcm {
+            samples: vec![1, -2],
+            sample_rate: 24000,
+            channels: 1,
+        };
+        assert_eq!(
+            encode(&pcm, AudioFormat::Pcm).unwrap(),
+            vec![1, 0, 0xfe, 0xff]
+        );
+        assert!(encode(&pcm, AudioFormat::Wav).unwrap().starts_with(b"RIFF"));
+    }
+
+    #[test]
+    fn disabled_formats_are_rejected() {
+        let pcm = Pcm {
+            samples: vec![0; 2400],
+            sample_rate: 24000,
+            channels: 1,
+        };
+        for format in AudioFormat::ALL {
+            match format.is_enabled() {
+                true => assert!(encode(&pcm, format).is_ok(), "{}", format),
+                false => assert_eq!(encode(&pcm, format), Err(AudioError::Disabled(format))),
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use super::{AudioError, AudioFormat, Pcm};
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, InterleavedPcm, MonoPcm, Quality};
+
+// sample rates accepted by LAME (MPEG-1, MPEG-2 and MPEG-2.5)
+const SUPPORTED_SAMPLE_RATES: [u32; 9] =
+    [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+pub(super) fn encode(pcm: &Pcm) -> Result<Vec<u8>, AudioError> {
+    let err = |msg: String| AudioError::Encode(AudioFormat::Mp3, msg);
+
+    let resampled;
+    let pcm = match SUPPORTED_SAMPLE_RATES.contains(&pcm.sample_rate) {
+        true => pcm,
+        false => {
+            resampled = pcm.resample(24000);
+            &resampled
+        }
+    };
+
+    let mut builder = Builder::new().ok_or_else(|| err("failed to create LAME encoder".into()))?;
+    builder
+        .set_num_channels(pcm.channels as u8)
+        .map_err(|e| err(e.to_string()))?;
+    builder
+        .set_sample_rate(pcm.sample_rate)
+        .map_err(|e| err(e.to_string()))?;
+    builder
+        .set_brate(Bitrate::Kbps64)
+        .map_err(|e| err(e.to_string()))?;
+    builder
+        .set_quality(Quality::Good)
+        .map_err(|e| err(e.to_string()))?;
+    let mut encoder = builder.build().map_err(|e| err(e.to_string()))?;
+
+    let mut buf = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(pcm.samples.len()));
+    match pcm.channels {
+        1 => encoder.encode_to_vec(MonoPcm(&pcm.samples), &mut buf),
+        _ => encoder.encode_to_vec(InterleavedPcm(&pcm.samples), &mut buf),
+    }
+    .map_err(|e| err(e.to_string()))?;
+    encoder
+        .flush_to_vec::<FlushNoGap>(&mut buf)
+        .map_err(|e| err(e.to_string()))?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(sample_rate: u32, channels: u16) -> Pcm {
+        let frames = sample_rate as usize / 2;
+        Pcm {
+            samples: (0..frames * channels as usize)
+                .map(|i| ((i as f64 * 0.05).sin() * 10000.0) as i16)
+                .collect(),
+            sample_rate,
+            channels,
+        }
+    }
+
+    // the 11-bit frame sync of an MPEG audio frame
+    fn starts_with_frame(buf: &[u8]) -> bool {
+        buf.len() > 4 && buf[0] == 0xff && buf[1] & 0xe0 == 0xe0
+    }
+
+    #[test]
+    fn encode_writes_mpeg_frames() {
+        for channels in [1, 2] {
+            let buf = encode(&tone(24000, channels)).unwrap();
+            assert!(starts_with_frame(&buf), "{} channels", channels);
+        }
+    }
+
+    #[test]
+    fn encode_resamples_unsupported_rates() {
+        let buf = encode(&tone(22000, 1)).unwrap();
+        assert!(starts_with_frame(&buf));
+    }
+}
@@ -0,0 +1,129 @@
+use super::{AudioError, AudioFormat, Pcm};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use opus::{Application, Channels, Encoder};
+
+// Opus always runs at 48kHz internally and granule positions are counted at that rate
+const OPUS_SAMPLE_RATE: u32 = 48000;
+// 20ms frames
+const FRAME_SIZE: usize = 960;
+
+pub(super) fn encode(pcm: &Pcm) -> Result<Vec<u8>, AudioError> {
+    let err = |msg: String| AudioError::Encode(AudioFormat::Opus, msg);
+
+    let channels = match pcm.channels {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        n => return Err(err(format!("unsupported channel count: {}", n))),
+    };
+    let mut encoder = Encoder::new(OPUS_SAMPLE_RATE, channels, Application::Voip)
+        .map_err(|e| err(e.to_string()))?;
+    let pre_skip = encoder.get_lookahead().map_err(|e| err(e.to_string()))? as u16;
+
+    let input_sample_rate = pcm.sample_rate;
+    let pcm = pcm.resample(OPUS_SAMPLE_RATE);
+    let frame_len = FRAME_SIZE * pcm.channels as usize;
+    let mut samples = pcm.samples;
+    let padded_len = samples.len().div_ceil(frame_len).max(1) * frame_len;
+    samples.resize(padded_len, 0);
+
+    // joined encodings form a chained Ogg file, whose logical streams need distinct serials
+    let serial = uuid::Uuid::new_v4().as_u128() as u32;
+    let mut writer = PacketWriter::new(Vec::new());
+
+    // identification header, see RFC 7845 section 5.1
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(pcm.channels as u8);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    writer
+        .write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)
+        .map_err(|e| err(e.to_string()))?;
+
+    // comment header, see RFC 7845 section 5.2
+    let vendor = concat!("tts-api-server ", env!("CARGO_PKG_VERSION"));
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    writer
+        .write_packet(tags, serial, PacketWriteEndInfo::EndPage, 0)
+        .map_err(|e| err(e.to_string()))?;
+
+    let mut out = vec![0u8; 4000];
+    let frames = samples.len() / frame_len;
+    for (i, frame) in samples.chunks_exact(frame_len).enumerate() {
+        let len = encoder
+            .encode(frame, &mut out)
+            .map_err(|e| err(e.to_string()))?;
+        let granule = ((i + 1) * FRAME_SIZE) as u64 + pre_skip as u64;
+        let end_info = match i + 1 == frames {
+            true => PacketWriteEndInfo::EndStream,
+            false => PacketWriteEndInfo::NormalPacket,
+        };
+        writer
+            .write_packet(out[..len].to_vec(), serial, end_info, granule)
+            .map_err(|e| err(e.to_string()))?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_writes_ogg_opus_headers() {
+        let pcm = Pcm {
+            samples: vec![1000; 24000],
+            sample_rate: 24000,
+            channels: 1,
+        };
+        let buf = encode(&pcm).unwrap();
+
+        assert_eq!(&buf[0..4], b"OggS");
+        // the first page holds only the identification header
+        let segments = buf[26] as usize;
+        let head = &buf[27 + segments..];
+        assert_eq!(&head[0..8], b"OpusHead");
+        assert_eq!(head[9], 1);
+        assert_eq!(
+            u32::from_le_bytes([head[12], head[13], head[14], head[15]]),
+            24000
+        );
+        assert!(buf.windows(8).any(|w| w == b"OpusTags"));
+    }
+
+    #[test]
+    fn encodings_can_be_chained() {
+        let pcm = Pcm {
+            samples: vec![1000; 4800],
+            sample_rate: 24000,
+            channels: 1,
+        };
+        // the bitstream serial number of the first page
+        let serial = |buf: &[u8]| u32::from_le_bytes([buf[14], buf[15], buf[16], buf[17]]);
+
+        let first = encode(&pcm).unwrap();
+        let second = encode(&pcm).unwrap();
+        assert_ne!(serial(&first), serial(&second));
+    }
+
+    #[test]
+    fn encode_rejects_more_than_two_channels() {
+        let pcm = Pcm {
+            samples: vec![0; 960 * 3],
+            sample_rate: 48000,
+            channels: 3,
+        };
+        assert!(matches!(
+            encode(&pcm),
+            Err(AudioError::Encode(AudioFormat::Opus, _))
+        ));
+    }
+}
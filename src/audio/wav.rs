@@ -0,0 +1,198 @@
+//! Minimal RIFF/WAVE reader and writer for 16-bit PCM audio.
+
+// the reader is only needed by the engines producing WAV, which the mock is not
+#![cfg_attr(not(any(feature = "piper", feature = "gpt_sovits")), allow(dead_code))]
+
+use super::{AudioError, Pcm};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decode a WAV buffer into interleaved 16-bit samples.
+///
+/// 16-bit integer and 32-bit float PCM are supported, which covers the output of both Piper and GPT-SoVITS.
+pub(crate) fn decode(buf: &[u8]) -> Result<Pcm, AudioError> {
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WAVE" {
+        return Err(AudioError::Decode("missing RIFF/WAVE header".to_string()));
+    }
+
+    let mut format: Option<(u16, u16, u32, u16)> = None;
+    let mut pos = 12;
+    while pos + 8 <= buf.len() {
+        let id = &buf[pos..pos + 4];
+        let size = u32::from_le_bytes([buf[pos + 4], buf[pos + 5], buf[pos + 6], buf[pos + 7]]);
+        let start = pos + 8;
+        // streaming writers leave the size as 0 or u32::MAX, so clamp it to what is actually there
+        let end = start.saturating_add(size as usize).min(buf.len());
+        let chunk = &buf[start..end];
+
+        match id {
+            b"fmt " => {
+                if chunk.len() < 16 {
+                    return Err(AudioError::Decode("truncated fmt chunk".to_string()));
+                }
+                let mut tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+                let channels = u16::from_le_bytes([chunk[2], chunk[3]]);
+                let sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+                let bits_per_sample = u16::from_le_bytes([chunk[14], chunk[15]]);
+                if tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                    // the first two bytes of the sub-format GUID carry the actual format tag
+                    tag = u16::from_le_bytes([chunk[24], chunk[25]]);
+                }
+                format = Some((tag, channels, sample_rate, bits_per_sample));
+            }
+            b"data" => {
+                let (tag, channels, sample_rate, bits_per_sample) = format.ok_or_else(|| {
+                    AudioError::Decode("data chunk found before fmt chunk".to_string())
+                })?;
+                if channels == 0 {
+                    return Err(AudioError::Decode("invalid channel count: 0".to_string()));
+                }
+
+                let samples = match (tag, bits_per_sample) {
+                    (WAVE_FORMAT_PCM, 16) => chunk
+                        .chunks_exact(2)
+                        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                        .collect(),
+                    (WAVE_FORMAT_IEEE_FLOAT, 32) => chunk
+                        .chunks_exact(4)
+                        .map(|b| {
+                            let v = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                            (v.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+                        })
+                        .collect(),
+                    (tag, bits) => {
+                        return Err(AudioError::Decode(format!(
+                            "unsupported sample format: tag {}, {} bits per sample",
+                            tag, bits
+                        )))
+                    }
+                };
+
+                return Ok(Pcm {
+                    samples,
+                    sample_rate,
+                    channels,
+                });
+            }
+            _ => {}
+        }
+
+        // chunks are padded to an even number of bytes
+        pos = end + (size as usize & 1);
+    }
+
+    Err(AudioError::Decode("missing data chunk".to_string()))
+}
+
+/// Encode 16-bit samples as a canonical 44-byte-header WAV file.
+pub(crate) fn encode(pcm: &Pcm) -> Vec<u8> {
+    let data_len = (pcm.samples.len() * 2) as u32;
+
+    let mut buf = header(pcm.sample_rate, pcm.channels, data_len);
+    buf.reserve(data_len as usize);
+    for sample in pcm.samples.iter() {
+        buf.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    buf
+}
+
+/// Build a 44-byte WAV header for `data_len` bytes of 16-bit PCM.
+pub(crate) fn header(sample_rate: u32, channels: u16, data_len: u32) -> Vec<u8> {
+    let block_align = channels * 2;
+    let byte_rate = sample_rate * block_align as u32;
+
+    let mut buf = Vec::with_capacity(44);
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&data_len.saturating_add(36).to_le_bytes());
+    buf.extend_from_slice(b"WAVE");
+    buf.extend_from_slice(b"fmt ");
+    buf.extend_from_slice(&16u32.to_le_bytes());
+    buf.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+    buf.extend_from_slice(&channels.to_le_bytes());
+    buf.extend_from_slice(&sample_rate.to_le_bytes());
+    buf.extend_from_slice(&byte_rate.to_le_bytes());
+    buf.extend_from_slice(&block_align.to_le_bytes());
+    buf.extend_from_slice(&16u16.to_le_bytes());
+    buf.extend_from_slice(b"data");
+    buf.extend_from_slice(&data_len.to_le_bytes());
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm() -> Pcm {
+        Pcm {
+            samples: vec![0, 1, -1, i16::MAX, i16::MIN, 1234],
+            sample_rate: 22050,
+            channels: 2,
+        }
+    }
+
+    #[test]
+    fn encode_then_decode_round_trips() {
+        let pcm = pcm();
+        let buf = encode(&pcm);
+        assert_eq!(buf.len(), 44 + pcm.samples.len() * 2);
+
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.samples, pcm.samples);
+        assert_eq!(decoded.sample_rate, 22050);
+        assert_eq!(decoded.channels, 2);
+    }
+
+    #[test]
+    fn decode_skips_unknown_chunks() {
+        let mut buf = encode(&pcm());
+        // an odd-sized LIST chunk, padded to an even length, before the data chunk
+        let list = [b"LIST".as_slice(), &3u32.to_le_bytes(), b"abc", &[0]].concat();
+        buf.splice(36..36, list);
+
+        assert_eq!(decode(&buf).unwrap().samples, pcm().samples);
+    }
+
+    #[test]
+    fn decode_clamps_a_streamed_data_size() {
+        let mut buf = encode(&pcm());
+        buf[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(decode(&buf).unwrap().samples, pcm().samples);
+    }
+
+    #[test]
+    fn decode_converts_float_samples() {
+        let mut buf = header(16000, 1, 12);
+        // format tag and bits per sample of 32-bit float PCM
+        buf[20..22].copy_from_slice(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+        buf[34..36].copy_from_slice(&32u16.to_le_bytes());
+        for v in [0.0f32, 1.0, -2.0] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.samples, vec![0, i16::MAX, -i16::MAX]);
+        assert_eq!(decoded.sample_rate, 16000);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_input() {
+        let mut no_fmt = encode(&pcm());
+        no_fmt.drain(12..36);
+        let mut eight_bit = encode(&pcm());
+        eight_bit[34..36].copy_from_slice(&8u16.to_le_bytes());
+
+        for buf in [
+            b"not a wav file".to_vec(),
+            encode(&pcm())[..36].to_vec(),
+            no_fmt,
+            eight_bit,
+        ] {
+            assert!(matches!(decode(&buf), Err(AudioError::Decode(_))));
+        }
+    }
+}
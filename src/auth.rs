@@ -0,0 +1,412 @@
+//! API key authentication and authorization of the incoming requests.
+//!
+//! When API keys are configured, every request needs an `Authorization: Bearer <API_KEY>` header,
+//! except for CORS preflight requests and the public routes. Each key can be limited to some routes
+//! and voices, and to a number of input characters per day. Rejected requests get a JSON error with
+//! the status `401`, `403` or `429`.
+//!
+//! Keys are only kept as SHA-256 digests, compared in constant time, and logged by name or by
+//! fingerprint, never in full.
+
+use crate::{config::ApiKeyConfig, error::ServerError};
+use hyper::{header::AUTHORIZATION, http::Method, Body, Request, Response};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use subtle::ConstantTimeEq;
+
+/// Routes served without an API key unless the configuration says otherwise.
+pub(crate) const DEFAULT_PUBLIC_ROUTES: [&str; 5] =
+    ["/echo", "/health", "/ready", "/version", "/queue"];
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// authentication settings, set at startup
+pub(crate) static AUTH: OnceCell<Auth> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Auth {
+    keys: Vec<ApiKey>,
+    public_routes: Vec<RoutePattern>,
+}
+impl Auth {
+    pub(crate) fn new(keys: Vec<ApiKey>, public_routes: Vec<RoutePattern>) -> Self {
+        Auth {
+            keys,
+            public_routes,
+        }
+    }
+
+    /// Check the API key of a request, returning the response to send back if it is rejected.
+    ///
+    /// The key of an accepted request is stored in the request extensions, see [`api_key`].
+    pub(crate) fn check(&'static self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        if self.keys.is_empty() {
+            return None;
+        }
+
+        // browsers send preflight requests without credentials
+        let (method, path) = (req.method(), req.uri().path());
+        if method == Method::OPTIONS
+            || self
+                .public_routes
+                .iter()
+                .any(|route| route.matches(method, path))
+        {
+            return None;
+        }
+
+        match self.authorize(req) {
+            Ok(api_key) => {
+                req.extensions_mut().insert(api_key);
+                None
+            }
+            Err(e) => Some(e.into_response()),
+        }
+    }
+
+    fn authorize(&'static self, req: &Request<Body>) -> Result<&'static ApiKey, ServerError> {
+        let token = bearer_token(req).map_err(|message| ServerError::Unauthorized {
+            code: "invalid_api_key",
+            message,
+        })?;
+        let key_hash = hash_key(token);
+        info!(target: "stdout", "API key fingerprint: {}", fingerprint(&key_hash));
+
+        // compare with every key, so that the time taken does not tell which one matched
+        let mut found = None;
+        for api_key in self.keys.iter() {
+            if bool::from(api_key.key_hash.ct_eq(&key_hash)) {
+                found = Some(api_key);
+            }
+        }
+        let api_key = found.ok_or_else(|| ServerError::Unauthorized {
+            code: "invalid_api_key",
+            message: "Invalid API key.".to_string(),
+        })?;
+
+        if api_key.revoked {
+            return Err(ServerError::Unauthorized {
+                code: "revoked_api_key",
+                message: format!("The API key `{}` has been revoked.", api_key.name),
+            });
+        }
+
+        let (method, path) = (req.method(), req.uri().path());
+        if let Some(routes) = api_key.routes.as_ref() {
+            if !routes.iter().any(|route| route.matches(method, path)) {
+                return Err(ServerError::Forbidden {
+                    code: "route_not_allowed",
+                    message: format!(
+                        "The API key `{}` is not allowed to access `{} {}`.",
+                        api_key.name, method, path
+                    ),
+                });
+            }
+        }
+
+        info!(target: "stdout", "API key name: {}", api_key.name);
+
+        Ok(api_key)
+    }
+}
+
+/// The API key that authenticated a request, if API keys are configured.
+pub(crate) fn api_key(req: &Request<Body>) -> Option<&'static ApiKey> {
+    req.extensions().get::<&'static ApiKey>().copied()
+}
+
+/// A named API key and what it may do.
+pub(crate) struct ApiKey {
+    pub(crate) name: String,
+    key_hash: [u8; 32],
+    routes: Option<Vec<RoutePattern>>,
+    voices: Option<Vec<String>>,
+    daily_char_quota: Option<u64>,
+    revoked: bool,
+    // the UTC day, as days since the Unix epoch, and the characters synthesized on that day
+    usage: Mutex<(u64, u64)>,
+}
+impl ApiKey {
+    /// A key allowed everything, e.g. the one of the `API_KEY` environment variable, given by its
+    /// SHA-256 digest.
+    pub(crate) fn unrestricted(name: impl Into<String>, key_hash: [u8; 32]) -> Self {
+        ApiKey {
+            name: name.into(),
+            key_hash,
+            routes: None,
+            voices: None,
+            daily_char_quota: None,
+            revoked: false,
+            usage: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Check that the key may use the voice named `voice`, which a request selected by `alias` if any.
+    ///
+    /// The voice is the one the request resolved to, so that a name falling back to another voice,
+    /// like an OpenAI voice name without an alias, does not grant that voice.
+    pub(crate) fn authorize_voice(
+        &self,
+        voice: &str,
+        alias: Option<&str>,
+    ) -> Result<(), ServerError> {
+        let Some(voices) = self.voices.as_ref() else {
+            return Ok(());
+        };
+
+        if voices
+            .iter()
+            .any(|v| v == voice || alias.is_some_and(|alias| v == alias))
+        {
+            return Ok(());
+        }
+
+        Err(ServerError::Forbidden {
+            code: "voice_not_allowed",
+            message: format!(
+                "The API key `{}` is not allowed to use the voice `{}`.",
+                self.name,
+                alias.unwrap_or(voice)
+            ),
+        })
+    }
+
+    /// Count `chars` synthesized characters against the daily quota of the key.
+    pub(crate) fn charge_chars(&self, chars: u64) -> Result<(), ServerError> {
+        let Some(quota) = self.daily_char_quota else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let day = now / SECS_PER_DAY;
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.0 != day {
+            *usage = (day, 0);
+        }
+        if usage.1 + chars > quota {
+            return Err(ServerError::QuotaExceeded {
+                message: format!(
+                    "The API key `{}` has used {} of its {} characters for today, and the request needs {}.",
+                    self.name, usage.1, quota, chars
+                ),
+                retry_after: SECS_PER_DAY - now % SECS_PER_DAY,
+            });
+        }
+        usage.1 += chars;
+
+        Ok(())
+    }
+
+    /// Give back `chars` characters counted today, for a request that failed.
+    pub(crate) fn refund_chars(&self, chars: u64) {
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+            / SECS_PER_DAY;
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.0 == day {
+            usage.1 = usage.1.saturating_sub(chars);
+        }
+    }
+}
+
+impl TryFrom<ApiKeyConfig> for ApiKey {
+    type Error = String;
+
+    fn try_from(config: ApiKeyConfig) -> Result<Self, Self::Error> {
+        let routes = match config.routes {
+            Some(routes) => Some(
+                routes
+                    .iter()
+                    .map(|route| route.parse())
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        let key_hash = match (config.key.as_deref(), config.key_sha256.as_deref()) {
+            (Some(key), None) => hash_key(key),
+            (None, Some(key_sha256)) => parse_key_hash(key_sha256)?,
+            _ => return Err("set either `key` or `key_sha256`".to_string()),
+        };
+
+        Ok(ApiKey {
+            name: config.name,
+            key_hash,
+            routes,
+            voices: config.voices,
+            daily_char_quota: config.daily_char_quota,
+            revoked: config.revoked,
+            usage: Mutex::new((0, 0)),
+        })
+    }
+}
+
+// leave the key digest out of debug output
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("routes", &self.routes)
+            .field("voices", &self.voices)
+            .field("daily_char_quota", &self.daily_char_quota)
+            .field("revoked", &self.revoked)
+            .finish_non_exhaustive()
+    }
+}
+
+/// SHA-256 digest of an API key.
+pub(crate) fn hash_key(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Parse a SHA-256 digest written as 64 hex digits, as `sha256sum` prints it.
+pub(crate) fn parse_key_hash(hex: &str) -> Result<[u8; 32], String> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("expected a SHA-256 digest of 64 hex digits".to_string());
+    }
+
+    let mut key_hash = [0u8; 32];
+    for (idx, byte) in key_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16)
+            .map_err(|_| "expected a SHA-256 digest of 64 hex digits".to_string())?;
+    }
+
+    Ok(key_hash)
+}
+
+/// The first 8 hex digits of a key digest, enough to tell keys apart in the logs.
+fn fingerprint(key_hash: &[u8; 32]) -> String {
+    key_hash[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A route, optionally limited to one HTTP method, e.g. `/v1/audio/speech` or `GET /v1/files*`.
+///
+/// A path ending with `*` matches every path starting with the rest of it.
+#[derive(Debug, Clone)]
+pub(crate) struct RoutePattern {
+    method: Option<Method>,
+    path: String,
+}
+impl RoutePattern {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        if self.method.as_ref().is_some_and(|m| m != method) {
+            return false;
+        }
+
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        }
+    }
+}
+impl FromStr for RoutePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (method, path) = match s.trim().split_once(' ') {
+            Some((method, path)) => {
+                let method = method
+                    .to_uppercase()
+                    .parse::<Method>()
+                    .map_err(|_| format!("`{}`: invalid HTTP method `{}`", s, method))?;
+                (Some(method), path.trim())
+            }
+            None => (None, s.trim()),
+        };
+
+        if !path.starts_with('/') {
+            return Err(format!("`{}`: the path does not start with `/`", s));
+        }
+
+        Ok(RoutePattern {
+            method,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Extract the token of an `Authorization: Bearer <token>` header.
+fn bearer_token(req: &Request<Body>) -> Result<&str, String> {
+    let auth_header = req.headers().get(AUTHORIZATION).ok_or_else(|| {
+        "Missing API key. Send it in an `Authorization: Bearer <API_KEY>` header.".to_string()
+    })?;
+
+    let auth_header = auth_header
+        .to_str()
+        .map_err(|e| format!("Failed to get authorization header: {}", e))?;
+
+    match auth_header.trim().split_once(' ') {
+        Some((scheme, token))
+            if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() =>
+        {
+            Ok(token.trim())
+        }
+        _ => Err("Malformed authorization header. Expected `Bearer <API_KEY>`.".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scoped_key(voices: &[&str]) -> ApiKey {
+        ApiKey {
+            voices: Some(voices.iter().map(|v| v.to_string()).collect()),
+            ..ApiKey::unrestricted("team", hash_key("sk-team"))
+        }
+    }
+
+    #[test]
+    fn voices_are_checked_after_resolution() {
+        let api_key = scoped_key(&["alloy", "lessac"]);
+
+        // an alias in the scope grants its voice
+        assert!(api_key.authorize_voice("amy", Some("alloy")).is_ok());
+        assert!(api_key.authorize_voice("lessac", None).is_ok());
+        // `alloy` without an alias falls back to the voice of the model
+        assert!(matches!(
+            api_key.authorize_voice("amy", None),
+            Err(ServerError::Forbidden {
+                code: "voice_not_allowed",
+                ..
+            })
+        ));
+        assert!(api_key.authorize_voice("amy", Some("nova")).is_err());
+        assert!(ApiKey::unrestricted("admin", hash_key("sk-admin"))
+            .authorize_voice("amy", None)
+            .is_ok());
+    }
+
+    #[test]
+    fn daily_quota_counts_characters() {
+        let api_key = ApiKey {
+            daily_char_quota: Some(10),
+            ..ApiKey::unrestricted("team", hash_key("sk-team"))
+        };
+
+        assert!(api_key.charge_chars(6).is_ok());
+        assert!(api_key.charge_chars(4).is_ok());
+        api_key.refund_chars(4);
+        assert!(api_key.charge_chars(4).is_ok());
+        match api_key.charge_chars(1) {
+            Err(ServerError::QuotaExceeded { retry_after, .. }) => {
+                assert!(retry_after > 0 && retry_after <= SECS_PER_DAY)
+            }
+            result => panic!("expected the quota to be exceeded, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! The OpenAI files API, served by llama-core from the `archives` directory.
+
+use crate::error::{self, ServerError};
+use hyper::{http::Method, Body, Request, Response};
+use std::path::Path;
+
+// where llama-core keeps each file, in a directory named by its id
+const ARCHIVES_DIR: &str = "archives";
+
+/// Download, retrieve and delete a file, or list all files.
+///
+/// - `GET /v1/files`: List all files.
+/// - `GET /v1/files/{file_id}`: Retrieve a file by id.
+/// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
+/// - `GET /v1/files/download/{file_id}`: Download a file by id.
+/// - `DELETE /v1/files/{file_id}`: Delete a file by id.
+///
+pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming files request");
+
+    let res = if req.method() == Method::GET {
+        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
+
+        // Split the path into segments
+        let segments: Vec<&str> = uri_path.split('/').collect();
+
+        match segments.as_slice() {
+            ["", "v1", "files"] => list_files(),
+            ["", "v1", "files", file_id, "content"] if file_id.starts_with("file_") => {
+                retrieve_file_content(file_id)
+            }
+            ["", "v1", "files", file_id] if file_id.starts_with("file_") => retrieve_file(file_id),
+            ["", "v1", "files", "download", file_id] => download_file(file_id),
+            _ => {
+                let err_msg = format!("unsupported uri path: {}", uri_path);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::not_found(err_msg)
+            }
+        }
+    } else if req.method() == Method::DELETE {
+        let id = match req.uri().path().strip_prefix("/v1/files/") {
+            Some(id) if !id.contains('/') => id,
+            _ => return error::method_not_allowed(req.method(), "GET"),
+        };
+        if let Err(e) = check_file_id(id) {
+            return e.into_response();
+        }
+        // llama-core reports a failed deletion in the status
+        let status = match llama_core::files::remove_file(id) {
+            Ok(status) if status.deleted => status,
+            Ok(_) => {
+                let err_msg = format!("Failed to delete the target file with id {}.", id);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+            Err(e) => {
+                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        // serialize status
+        let s = match serde_json::to_string(&status) {
+            Ok(s) => s,
+            Err(e) => {
+                let err_msg = format!(
+                    "Failed to serialize the status of the file deletion operation. {}",
+                    e
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        // return response
+        let result = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(s));
+
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else {
+        error::method_not_allowed(req.method(), "GET, DELETE")
+    };
+
+    info!(target: "stdout", "Send the files response");
+
+    res
+}
+
+fn list_files() -> Response<Body> {
+    match llama_core::files::list_files() {
+        Ok(file_objects) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&file_objects) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file list. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn retrieve_file(id: impl AsRef<str>) -> Response<Body> {
+    if let Err(e) = check_file_id(id.as_ref()) {
+        return e.into_response();
+    }
+
+    match llama_core::files::retrieve_file(id) {
+        Ok(fo) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&fo) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file object. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn retrieve_file_content(id: impl AsRef<str>) -> Response<Body> {
+    if let Err(e) = check_file_id(id.as_ref()) {
+        return e.into_response();
+    }
+
+    match llama_core::files::retrieve_file_content(id) {
+        Ok(content) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&content) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file content. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn download_file(id: impl AsRef<str>) -> Response<Body> {
+    if let Err(e) = check_file_id(id.as_ref()) {
+        return e.into_response();
+    }
+
+    match llama_core::files::download_file(id) {
+        Ok((filename, buffer)) => {
+            // get the extension of the file
+            let extension = filename.rsplit('.').next().unwrap_or("unknown");
+            let content_type = match extension {
+                "txt" => "text/plain",
+                "json" => "application/json",
+                "png" => "image/png",
+                "jpg" => "image/jpeg",
+                "jpeg" => "image/jpeg",
+                "wav" => "audio/wav",
+                "mp3" => "audio/mpeg",
+                "mp4" => "video/mp4",
+                "md" => "text/markdown",
+                _ => {
+                    let err_msg = format!("Unsupported file extension: {}", extension);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+            let content_disposition = format!("attachment; filename={}", filename);
+
+            // return response
+            let result = Response::builder()
+                .header("Content-Type", content_type)
+                .header("Content-Disposition", content_disposition)
+                .body(Body::from(buffer));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+// an unknown id is reported as not found, rather than as the I/O error of llama-core
+fn check_file_id(id: &str) -> Result<(), ServerError> {
+    // the id names a directory of the archives, and must not escape it
+    let is_valid = !id.is_empty()
+        && !id.contains("..")
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !is_valid {
+        let err_msg = format!("Invalid file id: {}", id);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(ServerError::BadRequest(err_msg));
+    }
+
+    if !id.starts_with("file_") || !Path::new(ARCHIVES_DIR).join(id).is_dir() {
+        let err_msg = format!("Not found the file with id {}", id);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(ServerError::NotFound(err_msg));
+    }
+
+    Ok(())
+}
@@ -1,5 +1,107 @@
-use crate::error;
-use hyper::{body::to_bytes, http::Method, Body, Request, Response};
+use super::{
+    speech::{self, SpeechRequest},
+    Synthesis, TtsBackend, Voice, CANARY,
+};
+use crate::{
+    audio, auth,
+    error::{self, ServerError},
+    limits, rate_limit,
+};
+use hyper::{http::Method, Body, Request, Response};
+use once_cell::sync::OnceCell;
+
+// the served GPT-SoVITS model, set at startup if the backend is enabled
+pub(crate) static GPT_SOVITS_MODEL: OnceCell<GptSovitsModel> = OnceCell::new();
+
+/// The model name under which GPT-SoVITS is served on `/v1/audio/speech`.
+#[derive(Debug)]
+pub(crate) struct GptSovitsModel {
+    pub(crate) name: String,
+    /// Seconds since the Unix epoch at which the backend was set up.
+    pub(crate) created: u64,
+    /// Speaker of the readiness checks. Without one the backend is not checked.
+    pub(crate) canary_speaker: Option<String>,
+}
+impl GptSovitsModel {
+    pub(crate) fn new(name: impl Into<String>, canary_speaker: Option<String>) -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        GptSovitsModel {
+            name: name.into(),
+            created,
+            canary_speaker,
+        }
+    }
+}
+
+/// GPT-SoVITS, run by the WasmEdge gpt_sovits plugin.
+pub(crate) struct GptSovitsBackend {
+    model: &'static GptSovitsModel,
+}
+impl GptSovitsBackend {
+    pub(crate) fn new(model: &'static GptSovitsModel) -> Self {
+        GptSovitsBackend { model }
+    }
+}
+impl TtsBackend for GptSovitsBackend {
+    fn name(&self) -> &'static str {
+        "gpt-sovits"
+    }
+
+    fn models(&self) -> Vec<String> {
+        vec![self.model.name.clone()]
+    }
+
+    fn created(&self) -> u64 {
+        self.model.created
+    }
+
+    fn resolve(&self, speech_request: &SpeechRequest) -> Result<Box<dyn Voice>, ServerError> {
+        let speaker = match speech_request.voice.as_deref() {
+            Some(speaker) if !speaker.is_empty() => speaker.to_string(),
+            _ => {
+                return Err(ServerError::InvalidValue {
+                    param: "voice",
+                    message: "GPT-SoVITS requires the speaker in the `voice` field.".to_string(),
+                })
+            }
+        };
+
+        info!(target: "stdout", "model: {} => gpt-sovits speaker: {}", &speech_request.model, &speaker);
+
+        Ok(Box::new(GptSovitsVoice { speaker }))
+    }
+
+    fn canary(&self) -> Vec<(String, Result<(), String>)> {
+        let result = match self.model.canary_speaker.as_deref() {
+            Some(speaker) => infer(speaker, CANARY)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            None => Ok(()),
+        };
+
+        vec![(self.model.name.clone(), result)]
+    }
+}
+
+struct GptSovitsVoice {
+    speaker: String,
+}
+impl Voice for GptSovitsVoice {
+    fn name(&self) -> &str {
+        &self.speaker
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Synthesis, String> {
+        let wav = infer(&self.speaker, text)?;
+        let pcm = audio::wav::decode(&wav).map_err(|e| e.to_string())?;
+
+        Ok(pcm.into())
+    }
+}
 
 mod ffi {
     #[link(wasm_import_module = "gpt_sovits")]
@@ -32,61 +134,42 @@ fn infer(speaker: &str, text: &str) -> Result<Vec<u8>, &'static str> {
     }
 }
 
+/// Request of the legacy `/v1/audio/speech_gpt` endpoint.
 #[derive(Debug, serde::Deserialize)]
-pub struct SpeechRequest {
+struct LegacySpeechRequest {
     /// The text to generate audio for.
-    pub input: String,
+    input: String,
     /// Id of speaker.
-    pub speaker: String,
-}
-
-fn create_speech(speech_request: SpeechRequest) -> anyhow::Result<Vec<u8>> {
-    let result =
-        infer(&speech_request.speaker, &speech_request.input).map_err(|e| anyhow::anyhow!(e))?;
-    Ok(result)
+    speaker: String,
+    /// The format of the audio. Defaults to `wav`.
+    #[serde(default)]
+    response_format: Option<String>,
 }
 
+/// Create speech with GPT-SoVITS from a `{"input": ..., "speaker": ...}` request.
+///
+/// - `POST /v1/audio/speech_gpt`: the legacy endpoint, kept for existing clients. New clients use
+///   `/v1/audio/speech` with the GPT-SoVITS model name.
 pub(crate) async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
     // log
     info!(target: "stdout", "Handling the coming audio speech request");
 
-    if req.method().eq(&Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
-
-                // log
-                error!(target: "stdout", "{}", &err_msg);
-
-                return error::internal_server_error(err_msg);
-            }
-        }
+    if req.method() != Method::POST {
+        return error::method_not_allowed(req.method(), "POST");
     }
 
     info!(target: "stdout", "Prepare the chat completion request.");
 
+    let api_key = auth::api_key(&req);
+    let client = rate_limit::client(&req).cloned();
+
     // parse request
-    let body_bytes = match to_bytes(req.into_body()).await {
+    let body_bytes = match limits::limits().read_body(req).await {
         Ok(body_bytes) => body_bytes,
-        Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return error::internal_server_error(err_msg);
-        }
+        Err(e) => return e.into_response(),
     };
-    let speech_request: SpeechRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(speech_request) => speech_request,
+    let legacy_request: LegacySpeechRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(legacy_request) => legacy_request,
         Err(e) => {
             let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
 
@@ -97,43 +180,35 @@ pub(crate) async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
         }
     };
 
-    let wav_data = match create_speech(speech_request) {
-        Ok(obj) => obj,
-        Err(e) => {
-            let err_msg = format!("Failed to transcribe the audio. {}", e);
+    let model = match GPT_SOVITS_MODEL.get() {
+        Some(model) => model.name.clone(),
+        None => {
+            let err_msg = "The GPT-SoVITS backend is not served.";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return error::not_found(err_msg);
         }
     };
 
-    // return response
-    let result = Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .header(hyper::header::CONTENT_TYPE, "audio/wav")
-        .header(
-            hyper::header::CONTENT_DISPOSITION,
-            "attachment; filename=audio.wav",
-        )
-        .body(Body::from(wav_data));
-
-    let res = match result {
-        Ok(response) => response,
-        Err(e) => {
-            let err_msg = e.to_string();
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            error::internal_server_error(err_msg)
-        }
-    };
-
-    info!(target: "stdout", "Send the audio speech response");
-
-    res
+    speech::speech_response(
+        SpeechRequest {
+            model,
+            input: legacy_request.input,
+            voice: Some(legacy_request.speaker),
+            response_format: Some(
+                legacy_request
+                    .response_format
+                    .unwrap_or_else(|| "wav".to_string()),
+            ),
+            speed: None,
+            speaker_id: None,
+            stream: false,
+            stream_format: None,
+        },
+        api_key,
+        client.as_ref(),
+    )
+    .await
 }
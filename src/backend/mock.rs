@@ -0,0 +1,87 @@
+//! A deterministic engine that synthesizes sine tones, so that the server runs without any WasmEdge plugin.
+//!
+//! The tone lasts 10 milliseconds per character of the text, and its pitch depends on the voice and
+//! the speaker only. The same request always produces the same audio.
+
+use super::{speech::SpeechRequest, Synthesis, TtsBackend, Voice};
+use crate::{audio::Pcm, error::ServerError};
+
+/// Model name of the mock backend.
+pub(crate) const MOCK_MODEL: &str = "mock";
+
+const SAMPLE_RATE: u32 = 22050;
+
+/// Samples per character of the text.
+const SAMPLES_PER_CHAR: usize = SAMPLE_RATE as usize / 100;
+
+pub(crate) struct MockBackend {
+    created: u64,
+}
+impl MockBackend {
+    pub(crate) fn new() -> Self {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        MockBackend { created }
+    }
+}
+impl TtsBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn models(&self) -> Vec<String> {
+        vec![MOCK_MODEL.to_string()]
+    }
+
+    fn created(&self) -> u64 {
+        self.created
+    }
+
+    fn resolve(&self, speech_request: &SpeechRequest) -> Result<Box<dyn Voice>, ServerError> {
+        let voice = speech_request.voice.as_deref().unwrap_or_default();
+
+        // FNV-1a, which unlike the std hasher is stable across runs
+        let mut hash: u32 = 0x811c_9dc5;
+        let speaker_id = speech_request.speaker_id.unwrap_or_default();
+        for byte in voice.bytes().chain(speaker_id.to_le_bytes()) {
+            hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+        }
+        let frequency = 220.0 + (hash % 440) as f32;
+
+        info!(target: "stdout", "model: {}, voice: {} => mock tone: {} Hz", &speech_request.model, if voice.is_empty() { "-" } else { voice }, frequency);
+
+        Ok(Box::new(MockVoice {
+            name: speech_request.voice_name().to_string(),
+            frequency,
+        }))
+    }
+}
+
+/// A tone for every voice name, or for the model when the request has no voice.
+struct MockVoice {
+    name: String,
+    frequency: f32,
+}
+impl Voice for MockVoice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Synthesis, String> {
+        let len = text.trim().chars().count() * SAMPLES_PER_CHAR;
+        let step = 2.0 * std::f32::consts::PI * self.frequency / SAMPLE_RATE as f32;
+        let samples = (0..len)
+            .map(|i| ((i as f32 * step).sin() * i16::MAX as f32 * 0.3) as i16)
+            .collect();
+
+        Ok(Pcm {
+            samples,
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+        }
+        .into())
+    }
+}
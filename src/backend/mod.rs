@@ -1,29 +1,229 @@
+#[cfg(feature = "piper")]
+pub(crate) mod files;
 #[cfg(feature = "gpt_sovits")]
 pub(crate) mod gpt_sovits;
+pub(crate) mod mock;
 #[cfg(feature = "piper")]
 pub(crate) mod piper;
+pub(crate) mod speech;
+#[cfg(feature = "piper")]
+pub(crate) mod voices;
+pub(crate) mod ws;
+
+use crate::{
+    audio::Pcm,
+    error::{self, ServerError},
+    metrics::MeasuredVoice,
+};
+
+use hyper::{http::Method, Body, Request, Response};
+use once_cell::sync::OnceCell;
+use speech::SpeechRequest;
+use std::sync::Arc;
+
+/// Phrase synthesized by the readiness checks.
+pub(crate) const CANARY: &str = "Ready.";
+
+// the served backends, in the order of `--backend`
+pub(crate) static BACKENDS: OnceCell<Vec<Box<dyn TtsBackend>>> = OnceCell::new();
+
+/// A TTS engine behind the speech endpoints.
+pub(crate) trait TtsBackend: Send + Sync {
+    /// Name of the backend, reported as the owner of its models.
+    fn name(&self) -> &'static str;
+
+    /// Names of the models served by the backend.
+    fn models(&self) -> Vec<String>;
+
+    /// Seconds since the Unix epoch at which the backend was set up.
+    fn created(&self) -> u64;
+
+    /// Whether a speech request for a model no backend serves is still served by this backend.
+    fn serves(&self, _speech_request: &SpeechRequest) -> bool {
+        false
+    }
+
+    /// Find the voice a speech request asks for, failing on an invalid request before anything is synthesized.
+    fn resolve(&self, speech_request: &SpeechRequest) -> Result<Box<dyn Voice>, ServerError>;
+
+    /// Synthesize [`CANARY`] with each voice, bypassing any cache, to tell whether the backend works.
+    /// Returns the result by voice.
+    fn canary(&self) -> Vec<(String, Result<(), String>)> {
+        self.models()
+            .into_iter()
+            .map(|model| {
+                let speech_request = SpeechRequest {
+                    model: model.clone(),
+                    input: CANARY.to_string(),
+                    voice: None,
+                    response_format: None,
+                    speed: None,
+                    speaker_id: None,
+                    stream: false,
+                    stream_format: None,
+                };
+                let result = self
+                    .resolve(&speech_request)
+                    .map_err(|e| e.to_string())
+                    .and_then(|voice| voice.synthesize(CANARY))
+                    .map(|_| ());
+
+                (model, result)
+            })
+            .collect()
+    }
+
+    /// Details of the served models, reported by `/version`.
+    fn model_info(&self) -> Vec<serde_json::Value> {
+        self.models()
+            .into_iter()
+            .map(|model| serde_json::json!({ "id": model }))
+            .collect()
+    }
+}
+
+/// A voice resolved from a speech request.
+pub(crate) trait Voice: Send {
+    /// Name of the voice, whichever model or alias of the request selected it.
+    fn name(&self) -> &str;
+
+    /// The alias in the `voice` field of the request, if the voice was selected by one.
+    fn alias(&self) -> Option<&str> {
+        None
+    }
+
+    /// Synthesize `text` into mono or multi-channel 16-bit samples.
+    fn synthesize(&self, text: &str) -> Result<Synthesis, String>;
+}
+
+/// The audio of a text, and whether it came from the speech cache rather than the backend.
+pub(crate) struct Synthesis {
+    pub(crate) pcm: Arc<Pcm>,
+    pub(crate) cached: bool,
+}
+impl From<Pcm> for Synthesis {
+    fn from(pcm: Pcm) -> Self {
+        Synthesis {
+            pcm: Arc::new(pcm),
+            cached: false,
+        }
+    }
+}
+
+/// Find the voice of a speech request with the backend serving its model.
+pub(crate) fn resolve(speech_request: &SpeechRequest) -> Result<Box<dyn Voice>, ServerError> {
+    let backends = match BACKENDS.get() {
+        Some(backends) if !backends.is_empty() => backends,
+        _ => {
+            return Err(ServerError::Unavailable {
+                message: "No TTS backend is served.".to_string(),
+                retry_after: None,
+            })
+        }
+    };
 
-use crate::error;
+    // a backend of the model first, then one taking the request anyway
+    let backend = backends
+        .iter()
+        .find(|backend| backend.models().contains(&speech_request.model))
+        .or_else(|| {
+            backends
+                .iter()
+                .find(|backend| backend.serves(speech_request))
+        });
+    let backend = match backend {
+        Some(backend) => backend,
+        None => {
+            let models: Vec<String> = backends
+                .iter()
+                .flat_map(|backend| backend.models())
+                .collect();
 
-use hyper::{Body, Request, Response};
+            return Err(ServerError::InvalidValue {
+                param: "model",
+                message: format!(
+                    "Unknown model: `{}`. Available models: {}",
+                    speech_request.model,
+                    models.join(", ")
+                ),
+            });
+        }
+    };
+    let voice = backend.resolve(speech_request)?;
+
+    Ok(Box::new(MeasuredVoice { inner: voice }))
+}
 
 pub(crate) async fn handle_llama_request(req: Request<Body>) -> Response<Body> {
     match req.uri().path() {
-        #[cfg(feature = "piper")]
-        "/v1/audio/speech" => piper::audio_speech_handler(req).await,
+        "/v1/audio/speech" => speech::audio_speech_handler(req).await,
+        "/v1/audio/speech/ws" => ws::audio_speech_ws_handler(req).await,
         #[cfg(feature = "gpt_sovits")]
         "/v1/audio/speech_gpt" => gpt_sovits::audio_speech_handler(req).await,
         #[cfg(feature = "piper")]
-        "/v1/files" => piper::files_handler(req).await,
-        path => {
-            #[cfg(feature = "piper")]
-            if path.starts_with("/v1/files/") {
-                piper::files_handler(req).await
-            } else {
-                error::invalid_endpoint(path)
-            }
-            #[cfg(feature = "gpt_sovits")]
-            error::invalid_endpoint(path)
+        "/v1/audio/voices" => piper::voices_handler(req).await,
+        "/v1/models" => models_handler(req).await,
+        #[cfg(feature = "piper")]
+        "/v1/files" => files::files_handler(req).await,
+        #[cfg(feature = "piper")]
+        path if path.starts_with("/v1/files/") => files::files_handler(req).await,
+        path => error::invalid_endpoint(path),
+    }
+}
+
+/// List the models of the served backends in the OpenAI format. The Piper voices are models too, since
+/// the `model` field of a speech request selects a voice.
+///
+/// - `GET /v1/models`
+async fn models_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming models request");
+
+    if let Some(response) = check_get_method(&req) {
+        return response;
+    }
+
+    let mut data: Vec<serde_json::Value> = Vec::new();
+    for backend in BACKENDS.get().into_iter().flatten() {
+        data.extend(backend.models().into_iter().map(|model| {
+            serde_json::json!({
+                "id": model,
+                "object": "model",
+                "created": backend.created(),
+                "owned_by": backend.name(),
+            })
+        }));
+    }
+
+    let res = json_response(serde_json::json!({ "object": "list", "data": data }));
+
+    info!(target: "stdout", "Send the models response");
+
+    res
+}
+
+// Reject anything but `GET`.
+fn check_get_method(req: &Request<Body>) -> Option<Response<Body>> {
+    match req.method() == Method::GET {
+        true => None,
+        false => Some(error::method_not_allowed(req.method(), "GET")),
+    }
+}
+
+fn json_response(value: serde_json::Value) -> Response<Body> {
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
         }
     }
 }
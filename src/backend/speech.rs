@@ -0,0 +1,596 @@
+use super::Voice;
+use crate::{
+    access_log::SpeechInfo,
+    audio::{self, AudioFormat},
+    auth::{self, ApiKey},
+    error::{self, ServerError},
+    limits,
+    queue::{self, SynthesisPermit},
+    rate_limit::{self, Client},
+    text,
+    timing::{self, SharedTimings, Stage},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use hyper::{http::Method, Body, Request, Response};
+use serde::Deserialize;
+use std::time::Instant;
+
+/// Request of the speech endpoint: the OpenAI create speech request plus a few extensions.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct SpeechRequest {
+    /// Model to use: a Piper voice, unless `voice` names one, or the model of another backend.
+    pub(crate) model: String,
+    /// The text to generate audio for.
+    pub(crate) input: String,
+    /// Name of the voice, or one of the OpenAI voice names.
+    #[serde(default)]
+    pub(crate) voice: Option<String>,
+    /// One of `mp3`, `opus`, `aac`, `flac`, `wav` and `pcm`. Defaults to `mp3`.
+    #[serde(default)]
+    pub(crate) response_format: Option<String>,
+    /// Speed of the generated audio.
+    #[serde(default)]
+    pub(crate) speed: Option<f64>,
+    /// Speaker of a multi-speaker voice.
+    #[serde(default)]
+    pub(crate) speaker_id: Option<u32>,
+    /// Send the audio sentence by sentence with chunked transfer encoding.
+    #[serde(default)]
+    pub(crate) stream: bool,
+    /// `audio` or `sse`. Defaults to `audio`.
+    #[serde(default)]
+    pub(crate) stream_format: Option<String>,
+}
+impl SpeechRequest {
+    /// The voice of the request: `voice`, or else the voice named by `model`.
+    pub(crate) fn voice_name(&self) -> &str {
+        self.voice.as_deref().unwrap_or(&self.model)
+    }
+}
+
+pub(crate) async fn audio_speech_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech request");
+
+    if req.method() != Method::POST {
+        return error::method_not_allowed(req.method(), "POST");
+    }
+
+    let api_key = auth::api_key(&req);
+    let client = rate_limit::client(&req).cloned();
+
+    // parse request
+    let body_bytes = match limits::limits().read_body(req).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => return e.into_response(),
+    };
+    let speech_request: SpeechRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(speech_request) => speech_request,
+        Err(e) => {
+            let err_msg = format!("Fail to deserialize speech request: {msg}", msg = e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    speech_response(speech_request, api_key, client.as_ref()).await
+}
+
+/// Synthesize a parsed speech request with the backend serving its model, and build the response.
+///
+/// The voice and the input length are checked against `api_key`, the key that sent the request, and
+/// the input length against the rate limit of `client`.
+/// The synthesis waits for a slot of the synthesis queue.
+pub(crate) async fn speech_response(
+    speech_request: SpeechRequest,
+    api_key: Option<&'static ApiKey>,
+    client: Option<&Client>,
+) -> Response<Body> {
+    let timings = SharedTimings::default();
+    let speech_info = SpeechInfo {
+        voice: speech_request.voice_name().to_string(),
+        chars: speech_request.input.chars().count(),
+        timings: timings.clone(),
+    };
+
+    let mut response = synthesize_response(speech_request, api_key, client, &timings).await;
+    // a streamed response only has the stages done before it starts
+    timings.get().set_headers(response.headers_mut());
+    response.extensions_mut().insert(speech_info);
+
+    response
+}
+
+async fn synthesize_response(
+    speech_request: SpeechRequest,
+    api_key: Option<&'static ApiKey>,
+    client: Option<&Client>,
+    timings: &SharedTimings,
+) -> Response<Body> {
+    let format = match parse_format(speech_request.response_format.as_deref()) {
+        Ok(format) => format,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::invalid_value("response_format", err_msg);
+        }
+    };
+
+    // `stream_format: "sse"` sends base64 audio deltas as server-sent events, as OpenAI does
+    let stream_format = match speech_request.stream_format.as_deref() {
+        None | Some("audio") => StreamFormat::Audio,
+        Some("sse") => StreamFormat::Sse,
+        Some(stream_format) => {
+            let err_msg = format!(
+                "Unsupported stream format: `{}`. Supported formats: audio, sse",
+                stream_format
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::invalid_value("stream_format", err_msg);
+        }
+    };
+    if speech_request.stream
+        && stream_format == StreamFormat::Audio
+        && !matches!(format, AudioFormat::Wav | AudioFormat::Pcm)
+    {
+        let err_msg = format!(
+            "The `{}` response format cannot be streamed. Use `wav` or `pcm` instead.",
+            format
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::invalid_value("response_format", err_msg);
+    }
+    // clients join the deltas, so each must continue the previous one: a `wav` header or a `flac`
+    // or `aac` stream per sentence would break the audio
+    if stream_format == StreamFormat::Sse
+        && !matches!(
+            format,
+            AudioFormat::Pcm | AudioFormat::Mp3 | AudioFormat::Opus
+        )
+    {
+        let err_msg = format!(
+            "The `{}` response format cannot be sent as server-sent events. Use `pcm`, `mp3` or `opus` instead.",
+            format
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::invalid_value("response_format", err_msg);
+    }
+
+    if let Err(e) = limits::limits().check_input(&speech_request.input) {
+        return e.into_response();
+    }
+    if speech_request.input.trim().is_empty() {
+        let err_msg = "The input has no text to synthesize.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::unprocessable_entity("input", err_msg);
+    }
+
+    let resolved = match super::resolve(&speech_request) {
+        Ok(resolved) => resolved,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = authorize_voice(api_key, resolved.as_ref()) {
+        return e.into_response();
+    }
+
+    // the slot is held until the audio is synthesized, or streamed
+    let queued = Instant::now();
+    let permit = queue::synthesis_queue().acquire().await;
+    timings.add(Stage::Queue, queued.elapsed());
+    let permit = match permit {
+        Ok(permit) => permit,
+        Err(e) => return e.into_response(),
+    };
+
+    // only a request about to be synthesized counts against the limits
+    let charge = match charge_speech(api_key, client, &speech_request) {
+        Ok(charge) => charge,
+        Err(e) => return e.into_response(),
+    };
+
+    info!(target: "stdout", "response format: {}", format);
+
+    if let Some(speed) = speech_request.speed {
+        if speed != 1.0 {
+            warn!(target: "stdout", "speed {} is not supported and is ignored", speed);
+        }
+    }
+
+    if speech_request.stream || stream_format == StreamFormat::Sse {
+        return stream_speech(
+            resolved,
+            speech_request,
+            format,
+            stream_format,
+            permit,
+            charge,
+            timings.clone(),
+        );
+    }
+
+    let started = Instant::now();
+    let synthesis = resolved.synthesize(&speech_request.input);
+    let cached = synthesis.as_ref().is_ok_and(|synthesis| synthesis.cached);
+    timings.add_synthesis(started.elapsed(), cached);
+    drop(permit);
+    let pcm = match synthesis {
+        Ok(synthesis) => synthesis.pcm,
+        Err(e) => {
+            charge.refund();
+
+            let err_msg = format!("Failed to create the audio. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    timings.add_audio(pcm.duration(), cached);
+
+    // encode the synthesized audio in the requested format
+    let started = Instant::now();
+    let audio_buffer = audio::encode(&pcm, format);
+    timings.add(Stage::Encode, started.elapsed());
+    let audio_buffer = match audio_buffer {
+        Ok(audio_buffer) => audio_buffer,
+        Err(e) => {
+            charge.refund();
+
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=audio.{}", format.extension()),
+        )
+        .body(Body::from(audio_buffer));
+
+    let res = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the audio speech response");
+
+    res
+}
+
+/// How a streamed speech response is framed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum StreamFormat {
+    /// Raw audio bytes with chunked transfer encoding.
+    Audio,
+    /// Server-sent `speech.audio.delta` events carrying base64 audio, closed by `speech.audio.done`.
+    Sse,
+}
+
+/// Synthesize the input sentence by sentence and send each sentence as soon as it is ready.
+fn stream_speech(
+    resolved: Box<dyn Voice>,
+    speech_request: SpeechRequest,
+    format: AudioFormat,
+    stream_format: StreamFormat,
+    permit: SynthesisPermit,
+    mut charge: Charge,
+    timings: SharedTimings,
+) -> Response<Body> {
+    let started = Instant::now();
+    let sentences = text::split_sentences(&speech_request.input);
+    timings.add(Stage::Text, started.elapsed());
+    if sentences.is_empty() {
+        charge.refund();
+
+        let err_msg = "The input has no text to synthesize.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::unprocessable_entity("input", err_msg);
+    }
+
+    info!(target: "stdout", "Stream {} sentences as {}", sentences.len(), format);
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let _permit = permit;
+        let mut header_sent = false;
+        for (idx, sentence) in sentences.iter().enumerate() {
+            let started = Instant::now();
+            let synthesis = resolved.synthesize(sentence);
+            let cached = synthesis.as_ref().is_ok_and(|synthesis| synthesis.cached);
+            timings.add_synthesis(started.elapsed(), cached);
+
+            let started = Instant::now();
+            let chunk = synthesis.and_then(|synthesis| {
+                let pcm = synthesis.pcm;
+                timings.add_audio(pcm.duration(), cached);
+                match stream_format {
+                    StreamFormat::Sse => audio::encode(&pcm, format)
+                        .map(|buf| sse_delta(&buf))
+                        .map_err(|e| e.to_string()),
+                    StreamFormat::Audio => match format {
+                        AudioFormat::Pcm => Ok(pcm.resample(audio::PCM_SAMPLE_RATE).to_le_bytes()),
+                        _ => {
+                            let mut chunk = Vec::new();
+                            if !header_sent {
+                                // the total length is unknown, so the sizes are set to the maximum as most players expect
+                                chunk = audio::wav::header(pcm.sample_rate, pcm.channels, u32::MAX);
+                                header_sent = true;
+                            }
+                            chunk.extend_from_slice(&pcm.to_le_bytes());
+                            Ok(chunk)
+                        }
+                    },
+                }
+            });
+            timings.add(Stage::Encode, started.elapsed());
+
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err_msg) => {
+                    // log
+                    error!(target: "stdout", "Failed to synthesize sentence {}. {}", idx, err_msg);
+
+                    refund_unsent(&mut charge, &sentences, idx);
+
+                    match stream_format {
+                        StreamFormat::Sse => {
+                            let event = sse_event(serde_json::json!({
+                                "type": "error",
+                                "error": { "message": err_msg },
+                            }));
+                            let _ = sender.send_data(event.into()).await;
+                        }
+                        StreamFormat::Audio => sender.abort(),
+                    }
+                    return;
+                }
+            };
+
+            if sender.send_data(chunk.into()).await.is_err() {
+                warn!(target: "stdout", "The client closed the audio stream");
+
+                refund_unsent(&mut charge, &sentences, idx);
+                return;
+            }
+
+            // synthesis blocks the runtime, so let the connection write the chunk before the next sentence
+            tokio::task::yield_now().await;
+        }
+
+        if stream_format == StreamFormat::Sse {
+            let mut done = serde_json::json!({ "type": "speech.audio.done" });
+            // the headers were sent before any sentence was synthesized, so the timings come last
+            if timing::enabled() {
+                done["timings"] = timings.get().to_json();
+            }
+            let _ = sender.send_data(sse_event(done).into()).await;
+        }
+
+        info!(target: "stdout", "Finish streaming the audio speech response");
+    });
+
+    let builder = Response::builder();
+    let result = match stream_format {
+        StreamFormat::Sse => builder
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache"),
+        StreamFormat::Audio => builder
+            .header("Content-Type", format.content_type())
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=audio.{}", format.extension()),
+            ),
+    }
+    .body(body);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// Check that `api_key` may use the voice a request resolved to.
+pub(crate) fn authorize_voice(
+    api_key: Option<&'static ApiKey>,
+    voice: &dyn Voice,
+) -> Result<(), ServerError> {
+    match api_key {
+        Some(api_key) => api_key.authorize_voice(voice.name(), voice.alias()),
+        None => Ok(()),
+    }
+}
+
+/// Count the input of a request against the character rate limit of `client` and the quota of
+/// `api_key`.
+///
+/// Charge a request only once it is about to be synthesized, and refund it if it fails before any
+/// audio is sent. A stream refunds the sentences it did not send.
+pub(crate) fn charge_speech(
+    api_key: Option<&'static ApiKey>,
+    client: Option<&Client>,
+    speech_request: &SpeechRequest,
+) -> Result<Charge, ServerError> {
+    let chars = speech_request.input.chars().count() as u64;
+
+    if let Some(client) = client {
+        rate_limit::rate_limiter().check_chars(client, chars)?;
+    }
+
+    if let Some(api_key) = api_key {
+        if let Err(e) = api_key.charge_chars(chars) {
+            if let Some(client) = client {
+                rate_limit::rate_limiter().refund_chars(client, chars);
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(Charge {
+        api_key,
+        client: client.cloned(),
+        chars,
+    })
+}
+
+/// Characters counted against the rate limit of a client and the quota of an API key.
+pub(crate) struct Charge {
+    api_key: Option<&'static ApiKey>,
+    client: Option<Client>,
+    chars: u64,
+}
+impl Charge {
+    /// Give the characters back, for a request that failed.
+    pub(crate) fn refund(mut self) {
+        self.refund_part(self.chars);
+    }
+
+    /// Give back `chars` of the characters, for the part of a streamed request that was not sent.
+    pub(crate) fn refund_part(&mut self, chars: u64) {
+        let chars = chars.min(self.chars);
+        self.chars -= chars;
+
+        if let Some(client) = self.client.as_ref() {
+            rate_limit::rate_limiter().refund_chars(client, chars);
+        }
+        if let Some(api_key) = self.api_key {
+            api_key.refund_chars(chars);
+        }
+    }
+}
+
+// the sentences from `idx` on were not sent, so they are not charged; nothing is if none was sent
+fn refund_unsent(charge: &mut Charge, sentences: &[String], idx: usize) {
+    let unsent = match idx {
+        0 => u64::MAX,
+        _ => sentences[idx..]
+            .iter()
+            .map(|sentence| sentence.chars().count() as u64)
+            .sum(),
+    };
+    charge.refund_part(unsent);
+}
+
+fn sse_event(event: serde_json::Value) -> Vec<u8> {
+    format!("data: {}\n\n", event).into_bytes()
+}
+
+fn sse_delta(audio: &[u8]) -> Vec<u8> {
+    sse_event(serde_json::json!({
+        "type": "speech.audio.delta",
+        "audio": BASE64_STANDARD.encode(audio),
+    }))
+}
+
+/// Parse `response_format`, accepting only formats enabled in this build.
+pub(crate) fn parse_format(response_format: Option<&str>) -> Result<AudioFormat, String> {
+    let format = match response_format {
+        None => AudioFormat::default(),
+        Some(format) => format.parse::<AudioFormat>().map_err(|_| {
+            format!(
+                "Unsupported response format: `{}`. Supported formats: {}",
+                format,
+                supported_formats()
+            )
+        })?,
+    };
+
+    match format.is_enabled() {
+        true => Ok(format),
+        false => Err(format!(
+            "The `{}` response format is not enabled on this server. Supported formats: {}",
+            format,
+            supported_formats()
+        )),
+    }
+}
+
+fn supported_formats() -> String {
+    AudioFormat::enabled()
+        .iter()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the JSON payload of one server-sent event
+    fn parse(event: &[u8]) -> serde_json::Value {
+        let event = std::str::from_utf8(event).unwrap();
+        let data = event
+            .strip_prefix("data: ")
+            .and_then(|event| event.strip_suffix("\n\n"))
+            .unwrap();
+        assert!(!data.contains('\n'));
+
+        serde_json::from_str(data).unwrap()
+    }
+
+    #[test]
+    fn events_are_single_data_lines() {
+        let event = sse_event(serde_json::json!({
+            "type": "error",
+            "error": { "message": "line one\nline two" },
+        }));
+
+        assert_eq!(parse(&event)["error"]["message"], "line one\nline two");
+    }
+
+    #[test]
+    fn deltas_carry_base64_audio() {
+        let audio: Vec<u8> = (0..=255).collect();
+
+        let event = parse(&sse_delta(&audio));
+        assert_eq!(event["type"], "speech.audio.delta");
+        let decoded = BASE64_STANDARD
+            .decode(event["audio"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(decoded, audio);
+
+        assert_eq!(parse(&sse_delta(&[]))["audio"], "");
+    }
+}
@@ -0,0 +1,460 @@
+//! Registry of the Piper voices loaded at startup.
+
+use super::{Synthesis, Voice};
+use crate::{
+    audio::{self, Pcm},
+    cache::{CacheKey, SPEECH_CACHE},
+    error::ServerError,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use wasmedge_wasi_nn::{ExecutionTarget, GraphBuilder, GraphEncoding, TensorType};
+
+// OpenAI voice names, which select the voice named by `model` unless they are aliased
+const OPENAI_VOICES: [&str; 6] = ["alloy", "echo", "fable", "onyx", "nova", "shimmer"];
+
+// Piper voices loaded at startup
+pub(crate) static PIPER_VOICES: OnceCell<VoiceRegistry> = OnceCell::new();
+
+/// Location of the files of a Piper voice.
+#[derive(Debug, Clone)]
+pub(crate) struct VoiceSpec {
+    /// Name used in the `model` or `voice` field of a speech request.
+    pub(crate) name: String,
+    /// Path to the ONNX model file.
+    pub(crate) model: PathBuf,
+    /// Path to the voice config file.
+    pub(crate) config: PathBuf,
+}
+impl std::str::FromStr for VoiceSpec {
+    type Err = String;
+
+    /// Parse `NAME=MODEL[,CONFIG]`. The config defaults to `MODEL.json`, as Piper voices are published.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, paths) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid voice `{}`. Expected NAME=MODEL[,CONFIG]", s))?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Invalid voice `{}`. The name is empty", s));
+        }
+
+        let (model, config) = match paths.split_once(',') {
+            Some((model, config)) => (PathBuf::from(model), PathBuf::from(config)),
+            None => {
+                let model = PathBuf::from(paths);
+                let mut config = model.clone().into_os_string();
+                config.push(".json");
+                (model, PathBuf::from(config))
+            }
+        };
+
+        Ok(VoiceSpec {
+            name: name.to_string(),
+            model,
+            config,
+        })
+    }
+}
+
+/// Maps a name used in the `voice` field of a request, e.g. an OpenAI voice name, to a loaded voice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VoiceAlias {
+    /// Name used in the `voice` field of a speech request.
+    pub(crate) name: String,
+    /// Name of the loaded voice.
+    pub(crate) voice: String,
+    /// Speaker id, or speaker name from the `speaker_id_map` of the voice config.
+    pub(crate) speaker: Option<String>,
+}
+impl std::str::FromStr for VoiceAlias {
+    type Err = String;
+
+    /// Parse `ALIAS=VOICE[:SPEAKER]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, target) = s.split_once('=').ok_or_else(|| {
+            format!(
+                "Invalid voice alias `{}`. Expected ALIAS=VOICE[:SPEAKER]",
+                s
+            )
+        })?;
+        let (voice, speaker) = match target.split_once(':') {
+            Some((voice, speaker)) => (voice, Some(speaker.trim().to_string())),
+            None => (target, None),
+        };
+
+        let name = name.trim();
+        let voice = voice.trim();
+        if name.is_empty() || voice.is_empty() {
+            return Err(format!(
+                "Invalid voice alias `{}`. Expected ALIAS=VOICE[:SPEAKER]",
+                s
+            ));
+        }
+
+        Ok(VoiceAlias {
+            name: name.to_string(),
+            voice: voice.to_string(),
+            speaker,
+        })
+    }
+}
+
+/// The voice and speaker a request resolved to.
+pub(crate) struct ResolvedVoice {
+    pub(crate) voice: &'static PiperVoice,
+    pub(crate) speaker_id: Option<u32>,
+    pub(crate) alias: Option<&'static str>,
+}
+impl Voice for ResolvedVoice {
+    fn name(&self) -> &str {
+        &self.voice.name
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.alias
+    }
+
+    /// Synthesize `text`, serving repeated texts from the speech cache if it is enabled.
+    fn synthesize(&self, text: &str) -> Result<Synthesis, String> {
+        let cache = SPEECH_CACHE.get();
+        let key = CacheKey {
+            voice: self.voice.name.clone(),
+            speaker_id: self.speaker_id,
+            text: text.to_string(),
+        };
+        if let Some(pcm) = cache.and_then(|cache| cache.get(&key)) {
+            return Ok(Synthesis { pcm, cached: true });
+        }
+
+        let synthesis = Synthesis::from(self.voice.synthesize(text, self.speaker_id)?);
+        if let Some(cache) = cache {
+            cache.insert(key, synthesis.pcm.clone());
+        }
+
+        Ok(synthesis)
+    }
+}
+
+/// The subset of a Piper voice config (`*.onnx.json`) used by the server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct VoiceConfig {
+    pub(crate) audio: AudioConfig,
+    #[serde(default)]
+    pub(crate) language: Option<LanguageConfig>,
+    #[serde(default = "default_num_speakers")]
+    pub(crate) num_speakers: u32,
+    #[serde(default)]
+    pub(crate) speaker_id_map: BTreeMap<String, u32>,
+    #[serde(default)]
+    pub(crate) dataset: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct AudioConfig {
+    pub(crate) sample_rate: u32,
+    #[serde(default)]
+    pub(crate) quality: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct LanguageConfig {
+    pub(crate) code: String,
+    #[serde(default)]
+    pub(crate) name_english: Option<String>,
+}
+
+fn default_num_speakers() -> u32 {
+    1
+}
+
+/// A loaded Piper voice.
+pub(crate) struct PiperVoice {
+    pub(crate) name: String,
+    pub(crate) config: VoiceConfig,
+    graph: Mutex<wasmedge_wasi_nn::Graph>,
+}
+impl PiperVoice {
+    fn load(spec: &VoiceSpec, espeak_ng_dir: &Path) -> Result<Self, ServerError> {
+        let config_str = std::fs::read_to_string(&spec.config).map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to read the config of voice `{}` from {}. {}",
+                spec.name,
+                spec.config.display(),
+                e
+            ))
+        })?;
+        let config: VoiceConfig = serde_json::from_str(&config_str).map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to parse the config of voice `{}`. {}",
+                spec.name, e
+            ))
+        })?;
+
+        // the piper plugin takes its settings as a json document
+        let graph_config = serde_json::json!({
+            "model": spec.model.to_string_lossy(),
+            "config": spec.config.to_string_lossy(),
+            "espeak_data": espeak_ng_dir.to_string_lossy(),
+            "json_input": true,
+        });
+        let graph = GraphBuilder::new(GraphEncoding::Piper, ExecutionTarget::CPU)
+            .build_from_bytes([graph_config.to_string().as_bytes()])
+            .map_err(|e| {
+                ServerError::Operation(format!(
+                    "Failed to load voice `{}` from {}. {}",
+                    spec.name,
+                    spec.model.display(),
+                    e
+                ))
+            })?;
+
+        Ok(PiperVoice {
+            name: spec.name.clone(),
+            config,
+            graph: Mutex::new(graph),
+        })
+    }
+
+    /// Synthesize `text`, optionally with one of the speakers of a multi-speaker voice.
+    pub(crate) fn synthesize(
+        &self,
+        text: impl AsRef<str>,
+        speaker_id: Option<u32>,
+    ) -> Result<Pcm, String> {
+        let text = text.as_ref();
+
+        if let Some(speaker_id) = speaker_id {
+            if speaker_id >= self.config.num_speakers {
+                return Err(format!(
+                    "Invalid speaker id {} for voice `{}`, which has {} speakers",
+                    speaker_id, self.name, self.config.num_speakers
+                ));
+            }
+        }
+
+        let mut input = serde_json::json!({ "text": text });
+        if let Some(speaker_id) = speaker_id {
+            input["speaker_id"] = speaker_id.into();
+        }
+        let input = input.to_string();
+
+        let graph = self
+            .graph
+            .lock()
+            .map_err(|e| format!("Failed to lock voice `{}`. {}", self.name, e))?;
+        let mut context = graph
+            .init_execution_context()
+            .map_err(|e| format!("Failed to init the execution context. {}", e))?;
+        context
+            .set_input(0, TensorType::U8, &[1], input.as_bytes())
+            .map_err(|e| format!("Failed to set the input. {}", e))?;
+        context
+            .compute()
+            .map_err(|e| format!("Failed to synthesize the audio. {}", e))?;
+
+        let mut buf = vec![0u8; self.output_buffer_size(text)];
+        let size = context
+            .get_output(0, &mut buf)
+            .map_err(|e| format!("Failed to get the output. {}", e))?;
+        buf.truncate(size);
+
+        audio::wav::decode(&buf).map_err(|e| e.to_string())
+    }
+
+    /// Look up a speaker by id or by name in the `speaker_id_map` of the voice config.
+    fn speaker_id(&self, speaker: &str) -> Result<u32, String> {
+        let speaker_id = match speaker.parse::<u32>() {
+            Ok(speaker_id) => speaker_id,
+            Err(_) => *self.config.speaker_id_map.get(speaker).ok_or_else(|| {
+                format!("Voice `{}` has no speaker named `{}`", self.name, speaker)
+            })?,
+        };
+
+        match speaker_id < self.config.num_speakers {
+            true => Ok(speaker_id),
+            false => Err(format!(
+                "Invalid speaker id {} for voice `{}`, which has {} speakers",
+                speaker_id, self.name, self.config.num_speakers
+            )),
+        }
+    }
+
+    // the plugin fails if the output does not fit, so leave room for slow speech and long pauses
+    fn output_buffer_size(&self, text: &str) -> usize {
+        let seconds = text.chars().count() as f64 * 0.25 + 5.0;
+        44 + (seconds * self.config.audio.sample_rate as f64) as usize * 2
+    }
+}
+
+/// Piper voices by name, and the aliases pointing at them.
+pub(crate) struct VoiceRegistry {
+    voices: BTreeMap<String, PiperVoice>,
+    aliases: BTreeMap<String, (String, Option<u32>)>,
+    created: u64,
+}
+impl VoiceRegistry {
+    /// Load every voice in `specs`, sharing one espeak-ng data directory, and check the aliases against them.
+    pub(crate) fn load(
+        specs: &[VoiceSpec],
+        aliases: &[VoiceAlias],
+        espeak_ng_dir: &Path,
+    ) -> Result<Self, ServerError> {
+        let mut voices = BTreeMap::new();
+        for spec in specs {
+            if voices.contains_key(&spec.name) {
+                return Err(ServerError::Operation(format!(
+                    "Duplicate voice name: {}",
+                    spec.name
+                )));
+            }
+
+            info!(target: "stdout", "load voice `{}`: model {}, config {}", spec.name, spec.model.display(), spec.config.display());
+
+            let voice = PiperVoice::load(spec, espeak_ng_dir)?;
+
+            info!(target: "stdout", "voice `{}`: sample rate {}, {} speakers", voice.name, voice.config.audio.sample_rate, voice.config.num_speakers);
+
+            voices.insert(spec.name.clone(), voice);
+        }
+
+        if voices.is_empty() {
+            return Err(ServerError::Operation(
+                "No voice is configured. Use `--model` and `--config`, or `--voice`.".to_string(),
+            ));
+        }
+
+        let mut resolved_aliases = BTreeMap::new();
+        for alias in aliases {
+            if voices.contains_key(&alias.name) {
+                return Err(ServerError::Operation(format!(
+                    "Voice alias `{}` shadows the voice of the same name",
+                    alias.name
+                )));
+            }
+            let voice = voices.get(&alias.voice).ok_or_else(|| {
+                ServerError::Operation(format!(
+                    "Voice alias `{}` points at unknown voice `{}`",
+                    alias.name, alias.voice
+                ))
+            })?;
+            let speaker_id = match alias.speaker.as_deref() {
+                Some(speaker) => Some(voice.speaker_id(speaker).map_err(|e| {
+                    ServerError::Operation(format!("Voice alias `{}`: {}", alias.name, e))
+                })?),
+                None => None,
+            };
+
+            info!(target: "stdout", "voice alias `{}`: voice `{}`, speaker {:?}", alias.name, alias.voice, speaker_id);
+
+            resolved_aliases.insert(alias.name.clone(), (alias.voice.clone(), speaker_id));
+        }
+
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Ok(VoiceRegistry {
+            voices,
+            aliases: resolved_aliases,
+            created,
+        })
+    }
+
+    /// Find the voice and speaker of a request.
+    ///
+    /// `voice` is looked up among the voices and then the aliases. An unaliased OpenAI voice name, or no
+    /// `voice` at all, selects the voice named by `model`. A `speaker_id` in the request takes precedence
+    /// over the speaker of an alias.
+    pub(crate) fn resolve(
+        &'static self,
+        model: &str,
+        voice: Option<&str>,
+        speaker_id: Option<u32>,
+    ) -> Result<ResolvedVoice, ServerError> {
+        let (name, alias, alias_speaker_id) = match voice {
+            Some(voice) if self.voices.contains_key(voice) => (voice, None, None),
+            Some(voice) => match self.aliases.get_key_value(voice) {
+                Some((alias, (name, alias_speaker_id))) => {
+                    (name.as_str(), Some(alias.as_str()), *alias_speaker_id)
+                }
+                None if OPENAI_VOICES.contains(&voice) => (model, None, None),
+                None => {
+                    return Err(ServerError::InvalidValue {
+                        param: "voice",
+                        message: format!(
+                            "Unknown voice: `{}`. Available voices: {}",
+                            voice,
+                            self.names().join(", ")
+                        ),
+                    })
+                }
+            },
+            None => (model, None, None),
+        };
+
+        let piper_voice = self
+            .voices
+            .get(name)
+            .ok_or_else(|| ServerError::InvalidValue {
+                param: "model",
+                message: format!(
+                    "Unknown model: `{}`. Available voices: {}",
+                    name,
+                    self.names().join(", ")
+                ),
+            })?;
+
+        let speaker_id = speaker_id.or(alias_speaker_id);
+        if let Some(speaker_id) = speaker_id {
+            if speaker_id >= piper_voice.config.num_speakers {
+                return Err(ServerError::InvalidValue {
+                    param: "speaker_id",
+                    message: format!(
+                        "Invalid speaker id {} for voice `{}`, which has {} speakers",
+                        speaker_id, piper_voice.name, piper_voice.config.num_speakers
+                    ),
+                });
+            }
+        }
+
+        Ok(ResolvedVoice {
+            voice: piper_voice,
+            speaker_id,
+            alias,
+        })
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &PiperVoice> {
+        self.voices.values()
+    }
+
+    /// Names of the aliases pointing at `voice`.
+    pub(crate) fn aliases_of(&self, voice: &str) -> Vec<&str> {
+        self.aliases
+            .iter()
+            .filter(|(_, (target, _))| target == voice)
+            .map(|(alias, _)| alias.as_str())
+            .collect()
+    }
+
+    /// Seconds since the Unix epoch at which the voices were loaded.
+    pub(crate) fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Whether `name` is a loaded voice or an alias of one.
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.voices.contains_key(name) || self.aliases.contains_key(name)
+    }
+
+    pub(crate) fn names(&self) -> Vec<&str> {
+        self.voices.keys().map(|name| name.as_str()).collect()
+    }
+}
@@ -0,0 +1,396 @@
+//! Incremental text-in / audio-out speech synthesis over a WebSocket.
+//!
+//! Client messages are JSON text frames:
+//!
+//! - `{"type": "config", "model": ..., "voice": ..., "response_format": ..., "speed": ...}`: set the
+//!   fields of the speech request used for the following segments.
+//! - `{"type": "text", "text": "..."}`: append a text fragment. Every completed sentence is synthesized
+//!   right away.
+//! - `{"type": "flush"}`: synthesize the buffered text even if the sentence is not complete.
+//! - `{"type": "close"}`: flush, then close the connection.
+//!
+//! For every segment the server sends a `segment.start` event, one binary frame holding the segment
+//! audio in `response_format` (`pcm` by default), and a `segment.end` event. Failures are reported as
+//! `error` events, and `done` is sent before the server closes the connection. Segments are checked
+//! against the voices and the daily quota of the API key that opened the connection.
+
+use super::speech::{self, SpeechRequest};
+use crate::{
+    access_log::RequestId,
+    audio::{self, AudioFormat},
+    auth::{self, ApiKey},
+    error, limits, queue,
+    rate_limit::{self, Client},
+    shutdown,
+    text::SentenceBuffer,
+};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use hyper::{header, http::Method, upgrade::Upgraded, Body, Request, Response, StatusCode};
+use serde_json::{json, Map, Value};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{Role, WebSocketConfig},
+        Message,
+    },
+    WebSocketStream,
+};
+
+type WsSink = SplitSink<WebSocketStream<Upgraded>, Message>;
+
+pub(crate) async fn audio_speech_ws_handler(mut req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming audio speech websocket request");
+
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    let is_upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    if !is_upgrade {
+        let err_msg = "Expected a WebSocket upgrade request.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    // RFC 6455 defines version 13 only
+    let version = req.headers().get(header::SEC_WEBSOCKET_VERSION);
+    if version.map(|version| version.as_bytes()) != Some(b"13") {
+        let err_msg = "Unsupported `Sec-WebSocket-Version`: only version 13 is supported.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        let mut response = error::bad_request(err_msg);
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_VERSION,
+            header::HeaderValue::from_static("13"),
+        );
+        return response;
+    }
+
+    let accept_key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            let err_msg = "Missing `Sec-WebSocket-Key` header.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    let api_key = auth::api_key(&req);
+    let client = rate_limit::client(&req).cloned();
+    // the session outlives the upgraded request, so it is tracked on its own for the shutdown
+    let in_flight = shutdown::shutdown().track(format!(
+        "WebSocket session request_id={}",
+        req.extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.as_str())
+            .unwrap_or_default()
+    ));
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(
+                    upgraded,
+                    Role::Server,
+                    Some(websocket_config()),
+                )
+                .await;
+                run_session(ws, api_key, client).await;
+            }
+            Err(e) => {
+                // log
+                error!(target: "stdout", "Failed to upgrade the connection to WebSocket. {}", e);
+            }
+        }
+    });
+
+    let result = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty());
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+async fn run_session(
+    ws: WebSocketStream<Upgraded>,
+    api_key: Option<&'static ApiKey>,
+    client: Option<Client>,
+) {
+    info!(target: "stdout", "WebSocket speech session started");
+
+    let (mut sink, mut stream) = ws.split();
+
+    let mut config = Map::new();
+    let mut format = AudioFormat::Pcm;
+    let mut buffer = SentenceBuffer::default();
+    let mut segment = 0;
+
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                warn!(target: "stdout", "WebSocket speech session failed: {}", e);
+                return;
+            }
+        };
+
+        let event: Value = match message {
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(event) => event,
+                Err(e) => {
+                    let err_msg = format!("Fail to deserialize the message: {}", e);
+                    if send_error(&mut sink, None, err_msg).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+            Message::Close(_) => break,
+            // pings are answered by tungstenite
+            _ => continue,
+        };
+
+        let (sentences, close) = match event.get("type").and_then(|t| t.as_str()) {
+            Some("config") => {
+                match update_config(&mut config, &mut format, event) {
+                    Ok(()) => {
+                        info!(target: "stdout", "WebSocket speech session config: {}", Value::Object(config.clone()))
+                    }
+                    Err(err_msg) => {
+                        if send_error(&mut sink, None, err_msg).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                continue;
+            }
+            Some("text") => match event.get("text").and_then(|t| t.as_str()) {
+                Some(text) => {
+                    let sentences = buffer.push(text);
+
+                    // a sentence that never ends would grow the buffer without bound
+                    let max_input_chars = limits::limits().max_input_chars;
+                    if max_input_chars > 0 && buffer.pending_chars() > max_input_chars {
+                        buffer.flush();
+                        let err_msg = format!(
+                            "The unfinished sentence exceeds the limit of {} characters and is dropped.",
+                            max_input_chars
+                        );
+                        if send_error(&mut sink, None, err_msg).await.is_err() {
+                            return;
+                        }
+                    }
+
+                    (sentences, false)
+                }
+                None => {
+                    let err_msg = "The `text` event requires a string `text` field.";
+                    if send_error(&mut sink, None, err_msg).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+            Some("flush") => (buffer.flush(), false),
+            Some("close") => (buffer.flush(), true),
+            _ => {
+                let err_msg = format!("Unsupported message: {}", event);
+                if send_error(&mut sink, None, err_msg).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        for sentence in sentences {
+            segment += 1;
+            if send_segment(
+                &mut sink,
+                &config,
+                api_key,
+                client.as_ref(),
+                format,
+                segment,
+                sentence,
+            )
+            .await
+            .is_err()
+            {
+                return;
+            }
+        }
+
+        if close {
+            break;
+        }
+    }
+
+    let _ = send_event(&mut sink, json!({ "type": "done" })).await;
+    let _ = sink.close().await;
+
+    info!(target: "stdout", "WebSocket speech session closed after {} segments", segment);
+}
+
+fn update_config(
+    config: &mut Map<String, Value>,
+    format: &mut AudioFormat,
+    event: Value,
+) -> Result<(), String> {
+    let Value::Object(fields) = event else {
+        return Err("The `config` event must be a JSON object.".to_string());
+    };
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "type" => continue,
+            "input" | "stream" | "stream_format" => {
+                return Err(format!("`{}` cannot be set in the config", key))
+            }
+            "response_format" => {
+                let value = value.as_str().unwrap_or_default();
+                let new_format = value
+                    .parse::<AudioFormat>()
+                    .ok()
+                    .filter(|format| format.is_enabled())
+                    .ok_or_else(|| format!("Unsupported response format: `{}`", value))?;
+                *format = new_format;
+            }
+            _ => {
+                config.insert(key, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_segment(
+    sink: &mut WsSink,
+    config: &Map<String, Value>,
+    api_key: Option<&'static ApiKey>,
+    client: Option<&Client>,
+    format: AudioFormat,
+    segment: u64,
+    sentence: String,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    send_event(
+        sink,
+        json!({ "type": "segment.start", "segment": segment, "text": &sentence }),
+    )
+    .await?;
+
+    let mut request = config.clone();
+    request.insert("input".to_string(), sentence.into());
+
+    let result = async {
+        let speech_request = serde_json::from_value::<SpeechRequest>(Value::Object(request))
+            .map_err(|e| format!("Fail to deserialize speech request: {}", e))?;
+        limits::limits()
+            .check_input(&speech_request.input)
+            .map_err(|e| e.to_string())?;
+        let resolved = super::resolve(&speech_request).map_err(|e| e.to_string())?;
+        speech::authorize_voice(api_key, resolved.as_ref()).map_err(|e| e.to_string())?;
+
+        let _permit = queue::synthesis_queue()
+            .acquire()
+            .await
+            .map_err(|e| e.to_string())?;
+        let charge =
+            speech::charge_speech(api_key, client, &speech_request).map_err(|e| e.to_string())?;
+        let result = resolved
+            .synthesize(&speech_request.input)
+            .and_then(|synthesis| {
+                audio::encode(&synthesis.pcm, format)
+                    .map(|buf| (buf, synthesis.pcm.duration()))
+                    .map_err(|e| e.to_string())
+            });
+        if result.is_err() {
+            charge.refund();
+        }
+
+        result
+    }
+    .await;
+
+    match result {
+        Ok((buf, duration)) => {
+            let bytes = buf.len();
+            sink.send(Message::Binary(buf)).await?;
+            send_event(
+                sink,
+                json!({
+                    "type": "segment.end",
+                    "segment": segment,
+                    "bytes": bytes,
+                    "duration": duration,
+                }),
+            )
+            .await
+        }
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "Failed to synthesize segment {}. {}", segment, &err_msg);
+
+            send_error(sink, Some(segment), err_msg).await
+        }
+    }
+}
+
+// messages are limited like request bodies
+fn websocket_config() -> WebSocketConfig {
+    let max_body_bytes = limits::limits().max_body_bytes;
+    let max_size = (max_body_bytes > 0).then_some(max_body_bytes);
+
+    WebSocketConfig {
+        max_message_size: max_size,
+        max_frame_size: max_size,
+        ..Default::default()
+    }
+}
+
+async fn send_error(
+    sink: &mut WsSink,
+    segment: Option<u64>,
+    msg: impl AsRef<str>,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut event = json!({ "type": "error", "message": msg.as_ref() });
+    if let Some(segment) = segment {
+        event["segment"] = segment.into();
+    }
+    send_event(sink, event).await
+}
+
+async fn send_event(
+    sink: &mut WsSink,
+    event: Value,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    sink.send(Message::Text(event.to_string())).await
+}
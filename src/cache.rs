@@ -0,0 +1,177 @@
+//! In-memory cache of synthesized sentences.
+
+use crate::audio::Pcm;
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// cache of synthesized audio, set up at startup if enabled
+pub(crate) static SPEECH_CACHE: OnceCell<SpeechCache> = OnceCell::new();
+
+/// Identifies a synthesized text: the voice, the speaker and the text itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    pub(crate) voice: String,
+    pub(crate) speaker_id: Option<u32>,
+    pub(crate) text: String,
+}
+
+/// Least-recently-used cache bounded by entry count and total audio size.
+pub(crate) struct SpeechCache {
+    max_entries: usize,
+    max_bytes: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    // the audio, and the tick of its last use
+    entries: HashMap<CacheKey, (u64, Arc<Pcm>)>,
+    // the keys by the tick of their last use, least recently used first
+    order: BTreeMap<u64, CacheKey>,
+    // incremented on each use
+    tick: u64,
+    bytes: usize,
+}
+impl CacheInner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl SpeechCache {
+    pub(crate) fn new(max_entries: usize, max_bytes: usize) -> Self {
+        SpeechCache {
+            max_entries,
+            max_bytes,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<Arc<Pcm>> {
+        let mut inner = self.inner.lock().ok()?;
+
+        let tick = inner.next_tick();
+        let Some((used, pcm)) = inner.entries.get_mut(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let last_used = std::mem::replace(used, tick);
+        let pcm = pcm.clone();
+        if let Some(key) = inner.order.remove(&last_used) {
+            inner.order.insert(tick, key);
+        }
+
+        Some(pcm)
+    }
+
+    /// Number of lookups that found the audio, and of those that did not.
+    pub(crate) fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn insert(&self, key: CacheKey, pcm: Arc<Pcm>) {
+        let size = pcm.samples.len() * 2;
+        if self.max_bytes > 0 && size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+
+        let tick = inner.next_tick();
+        if let Some((last_used, old)) = inner.entries.insert(key.clone(), (tick, pcm)) {
+            inner.bytes -= old.samples.len() * 2;
+            inner.order.remove(&last_used);
+        }
+        inner.order.insert(tick, key);
+        inner.bytes += size;
+
+        while inner.entries.len() > self.max_entries
+            || (self.max_bytes > 0 && inner.bytes > self.max_bytes)
+        {
+            let Some((_, evicted)) = inner.order.pop_first() else {
+                break;
+            };
+            if let Some((_, old)) = inner.entries.remove(&evicted) {
+                inner.bytes -= old.samples.len() * 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(text: &str) -> CacheKey {
+        CacheKey {
+            voice: "mock".to_string(),
+            speaker_id: None,
+            text: text.to_string(),
+        }
+    }
+
+    fn pcm(samples: usize) -> Arc<Pcm> {
+        Arc::new(Pcm {
+            samples: vec![0; samples],
+            sample_rate: 16000,
+            channels: 1,
+        })
+    }
+
+    #[test]
+    fn the_least_recently_used_entry_is_evicted() {
+        let cache = SpeechCache::new(2, 0);
+        cache.insert(key("a"), pcm(1));
+        cache.insert(key("b"), pcm(1));
+        assert!(cache.get(&key("a")).is_some());
+
+        cache.insert(key("c"), pcm(1));
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("c")).is_some());
+        assert_eq!(cache.hits_and_misses(), (3, 1));
+    }
+
+    #[test]
+    fn the_cache_is_bounded_by_size() {
+        let cache = SpeechCache::new(10, 8);
+        // larger than the whole cache
+        cache.insert(key("a"), pcm(5));
+        assert!(cache.get(&key("a")).is_none());
+
+        cache.insert(key("b"), pcm(2));
+        cache.insert(key("c"), pcm(2));
+        // replacing an entry counts its new size only
+        cache.insert(key("b"), pcm(1));
+        cache.insert(key("d"), pcm(1));
+        assert!(cache.get(&key("b")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+        assert!(cache.get(&key("d")).is_some());
+
+        cache.insert(key("e"), pcm(2));
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("e")).is_some());
+
+        let inner = cache.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), inner.order.len());
+        assert!(inner.bytes <= 8);
+    }
+}
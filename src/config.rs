@@ -0,0 +1,420 @@
+//! Server configuration file.
+//!
+//! The file is TOML, or YAML if its extension is `.yaml` or `.yml`. Settings are resolved in this order,
+//! later sources overriding earlier ones: built-in defaults, the configuration file, environment
+//! variables (`LLAMA_LOG`, `API_KEY`), and command line arguments.
+//!
+//! API keys can also be kept in a separate file, see `auth.keys_file`.
+
+use crate::{
+    access_log::AccessLogFormat,
+    auth::{self, RoutePattern},
+    cors::Cors,
+    error::ServerError,
+    Backend, LogLevel,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// TTS backends to serve: `piper`, `gpt-sovits` and `mock`. Defaults to all compiled-in engines.
+    pub(crate) backends: Vec<Backend>,
+    pub(crate) server: ServerConfig,
+    pub(crate) logging: LoggingConfig,
+    pub(crate) auth: AuthConfig,
+    pub(crate) piper: PiperConfig,
+    pub(crate) gpt_sovits: GptSovitsConfig,
+    pub(crate) cache: CacheConfig,
+    pub(crate) limits: LimitsConfig,
+    pub(crate) queue: QueueConfig,
+    pub(crate) rate_limit: RateLimitConfig,
+    pub(crate) cors: CorsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    /// Socket address to listen on, e.g. `0.0.0.0:8080`.
+    pub(crate) socket_addr: Option<SocketAddr>,
+    /// Port to listen on all interfaces.
+    pub(crate) port: Option<u16>,
+    /// Whether speech responses carry the time spent in each stage in `Server-Timing` and `X-TTS-*`
+    /// headers.
+    pub(crate) timing_headers: bool,
+    /// Seconds the requests in flight may run after `SIGTERM` or `SIGINT`. Defaults to 30.
+    pub(crate) shutdown_timeout_secs: Option<f64>,
+    /// Serve `POST /shutdown`, which shuts the server down like `SIGTERM`. WASI has no signals, so
+    /// this is how a server run by WasmEdge is drained.
+    pub(crate) shutdown_route: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    pub(crate) level: Option<LogLevel>,
+    /// Format of the access log records: `text` or `json`. Defaults to `text`.
+    pub(crate) access_log_format: Option<AccessLogFormat>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+    /// API key that clients must present as a bearer token, allowed everything. Named `default`.
+    pub(crate) api_key: Option<String>,
+    /// SHA-256 digest of the API key in hex, instead of `api_key`.
+    pub(crate) api_key_sha256: Option<String>,
+    /// Routes served without an API key, e.g. `/echo`. A trailing `*` matches any suffix. Defaults to
+    /// `["/echo", "/health", "/ready", "/version", "/queue"]`.
+    pub(crate) public_routes: Option<Vec<String>>,
+    /// Named API keys.
+    pub(crate) keys: Vec<ApiKeyConfig>,
+    /// File with more named API keys in a `keys` list, TOML or YAML like this file.
+    pub(crate) keys_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ApiKeyConfig {
+    /// Name of the key, shown in the logs.
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+    /// SHA-256 digest of the key in hex, instead of `key`.
+    #[serde(default)]
+    pub(crate) key_sha256: Option<String>,
+    /// Routes the key may access, optionally prefixed by an HTTP method, e.g. `GET /v1/files*`.
+    /// Defaults to all routes.
+    #[serde(default)]
+    pub(crate) routes: Option<Vec<String>>,
+    /// Voices the key may synthesize with; the model when a request has no voice. Defaults to all voices.
+    #[serde(default)]
+    pub(crate) voices: Option<Vec<String>>,
+    /// Maximum number of input characters the key may synthesize per UTC day.
+    #[serde(default)]
+    pub(crate) daily_char_quota: Option<u64>,
+    /// Reject the key.
+    #[serde(default)]
+    pub(crate) revoked: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KeysFile {
+    keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "piper"), allow(dead_code))]
+pub(crate) struct PiperConfig {
+    /// Path to the espeak-ng data directory.
+    pub(crate) espeak_ng_dir: Option<PathBuf>,
+    pub(crate) voices: Vec<VoiceConfig>,
+    /// Voice aliases, from the alias to `VOICE[:SPEAKER]`.
+    pub(crate) aliases: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "piper"), allow(dead_code))]
+pub(crate) struct VoiceConfig {
+    pub(crate) name: String,
+    /// Path to the ONNX model file.
+    pub(crate) model: PathBuf,
+    /// Path to the voice config file. Defaults to the model path with a `.json` suffix.
+    pub(crate) config: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "gpt_sovits"), allow(dead_code))]
+pub(crate) struct GptSovitsConfig {
+    /// Model name that selects GPT-SoVITS on `/v1/audio/speech`. Defaults to `gpt-sovits`.
+    pub(crate) model_name: Option<String>,
+    /// Speaker that synthesizes the readiness checks. Without one, `/ready` does not synthesize with
+    /// GPT-SoVITS.
+    pub(crate) canary_speaker: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "piper"), allow(dead_code))]
+pub(crate) struct CacheConfig {
+    /// Maximum number of synthesized sentences kept in memory. `0` disables the cache.
+    pub(crate) max_entries: usize,
+    /// Maximum total size of the cached audio in bytes. `0` means no limit besides `max_entries`.
+    pub(crate) max_bytes: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    /// Maximum size of a request body in bytes. `0` means no limit. Defaults to 1 MiB.
+    pub(crate) max_body_bytes: Option<usize>,
+    /// Maximum number of characters of a speech input. `0` means no limit. Defaults to 4096.
+    pub(crate) max_input_chars: Option<usize>,
+    /// Maximum estimated duration of the audio of a speech input in seconds. `0` means no limit.
+    /// Defaults to 600.
+    pub(crate) max_audio_seconds: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QueueConfig {
+    /// Maximum number of syntheses running at once. `0` means no limit. Defaults to 4.
+    pub(crate) max_concurrent: Option<usize>,
+    /// Maximum number of requests waiting for a synthesis slot. Defaults to 16.
+    pub(crate) max_queued: Option<usize>,
+    /// Seconds a request may wait for a synthesis slot. Defaults to 30.
+    pub(crate) timeout_secs: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    /// Requests per minute to the `/v1` routes, per API key or client address. `0` means no limit,
+    /// the default.
+    pub(crate) requests_per_minute: Option<u64>,
+    /// Characters synthesized per minute, per API key or client address. `0` means no limit, the
+    /// default.
+    pub(crate) chars_per_minute: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CorsConfig {
+    /// Origins allowed to call the server, e.g. `https://example.com`, or `*` for any. Defaults to
+    /// `["*"]`.
+    pub(crate) allowed_origins: Option<Vec<String>>,
+    /// Methods allowed in cross-origin requests, or `*` for any. Defaults to `["*"]`.
+    pub(crate) allowed_methods: Option<Vec<String>>,
+    /// Headers allowed in cross-origin requests, or `*` for any. Defaults to `["*"]`.
+    pub(crate) allowed_headers: Option<Vec<String>>,
+    /// Whether browsers may send cookies and other credentials. Needs the origins to be listed.
+    pub(crate) allow_credentials: bool,
+    /// Seconds browsers may cache the answer to a preflight request.
+    pub(crate) max_age_secs: Option<u64>,
+}
+
+impl Config {
+    /// Load and validate a configuration file.
+    ///
+    /// Relative paths in the file are resolved against the directory of the file.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+
+        let mut config: Config = parse(path)?;
+
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+
+        if let Some(keys_file) = config.auth.keys_file.as_ref() {
+            let keys: KeysFile = parse(keys_file)?;
+            config.auth.keys.extend(keys.keys);
+        }
+
+        config.validate().map_err(|e| {
+            ServerError::Operation(format!("Invalid config file {}: {}", path.display(), e))
+        })?;
+
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        };
+
+        if let Some(keys_file) = self.auth.keys_file.as_mut() {
+            resolve(keys_file);
+        }
+        if let Some(espeak_ng_dir) = self.piper.espeak_ng_dir.as_mut() {
+            resolve(espeak_ng_dir);
+        }
+        for voice in self.piper.voices.iter_mut() {
+            resolve(&mut voice.model);
+            if let Some(config) = voice.config.as_mut() {
+                resolve(config);
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.server.socket_addr.is_some() && self.server.port.is_some() {
+            return Err(
+                "`server.socket_addr` and `server.port` are mutually exclusive".to_string(),
+            );
+        }
+
+        if let Some(api_key) = self.auth.api_key.as_deref() {
+            if api_key.trim().is_empty() {
+                return Err("`auth.api_key` is empty".to_string());
+            }
+            if self.auth.api_key_sha256.is_some() {
+                return Err(
+                    "`auth.api_key` and `auth.api_key_sha256` are mutually exclusive".to_string(),
+                );
+            }
+        }
+        if let Some(api_key_sha256) = self.auth.api_key_sha256.as_deref() {
+            auth::parse_key_hash(api_key_sha256)
+                .map_err(|e| format!("`auth.api_key_sha256`: {}", e))?;
+        }
+
+        for (idx, route) in self.auth.public_routes.iter().flatten().enumerate() {
+            route
+                .parse::<RoutePattern>()
+                .map_err(|e| format!("`auth.public_routes[{}]`: {}", idx, e))?;
+        }
+
+        let mut names = HashSet::new();
+        let mut keys = HashSet::new();
+        for (idx, api_key) in self.auth.keys.iter().enumerate() {
+            if api_key.name.trim().is_empty() {
+                return Err(format!("`auth.keys[{}].name` is empty", idx));
+            }
+            if !names.insert(api_key.name.as_str()) {
+                return Err(format!(
+                    "`auth.keys[{}].name`: duplicate key name `{}`",
+                    idx, api_key.name
+                ));
+            }
+            let key_hash = match (api_key.key.as_deref(), api_key.key_sha256.as_deref()) {
+                (Some(key), None) if key.trim().is_empty() => {
+                    return Err(format!("`auth.keys[{}].key` is empty", idx))
+                }
+                (Some(key), None) => auth::hash_key(key),
+                (None, Some(key_sha256)) => auth::parse_key_hash(key_sha256)
+                    .map_err(|e| format!("`auth.keys[{}].key_sha256`: {}", idx, e))?,
+                _ => {
+                    return Err(format!(
+                        "`auth.keys[{}]`: set either `key` or `key_sha256`",
+                        idx
+                    ))
+                }
+            };
+            if !keys.insert(key_hash) {
+                return Err(format!(
+                    "`auth.keys[{}].key`: the key of `{}` is already used",
+                    idx, api_key.name
+                ));
+            }
+            for (route_idx, route) in api_key.routes.iter().flatten().enumerate() {
+                route
+                    .parse::<RoutePattern>()
+                    .map_err(|e| format!("`auth.keys[{}].routes[{}]`: {}", idx, route_idx, e))?;
+            }
+        }
+
+        if let Some(max_audio_seconds) = self.limits.max_audio_seconds {
+            if !max_audio_seconds.is_finite() || max_audio_seconds < 0.0 {
+                return Err(format!(
+                    "`limits.max_audio_seconds`: {} is not a number of seconds",
+                    max_audio_seconds
+                ));
+            }
+        }
+
+        if let Some(shutdown_timeout_secs) = self.server.shutdown_timeout_secs {
+            if !shutdown_timeout_secs.is_finite() || shutdown_timeout_secs < 0.0 {
+                return Err(format!(
+                    "`server.shutdown_timeout_secs`: {} is not a number of seconds",
+                    shutdown_timeout_secs
+                ));
+            }
+        }
+
+        if let Some(timeout_secs) = self.queue.timeout_secs {
+            if !timeout_secs.is_finite() || timeout_secs < 0.0 {
+                return Err(format!(
+                    "`queue.timeout_secs`: {} is not a number of seconds",
+                    timeout_secs
+                ));
+            }
+        }
+
+        Cors::try_from(&self.cors)?;
+
+        let mut names = HashSet::new();
+        for (idx, voice) in self.piper.voices.iter().enumerate() {
+            if voice.name.trim().is_empty() {
+                return Err(format!("`piper.voices[{}].name` is empty", idx));
+            }
+            if !names.insert(voice.name.as_str()) {
+                return Err(format!(
+                    "`piper.voices[{}].name`: duplicate voice name `{}`",
+                    idx, voice.name
+                ));
+            }
+            if !voice.model.is_file() {
+                return Err(format!(
+                    "`piper.voices[{}].model`: file not found: {}",
+                    idx,
+                    voice.model.display()
+                ));
+            }
+            if let Some(config) = voice.config.as_ref() {
+                if !config.is_file() {
+                    return Err(format!(
+                        "`piper.voices[{}].config`: file not found: {}",
+                        idx,
+                        config.display()
+                    ));
+                }
+            }
+        }
+
+        if let Some(espeak_ng_dir) = self.piper.espeak_ng_dir.as_ref() {
+            if !espeak_ng_dir.is_dir() {
+                return Err(format!(
+                    "`piper.espeak_ng_dir`: directory not found: {}",
+                    espeak_ng_dir.display()
+                ));
+            }
+        }
+
+        for (alias, target) in self.piper.aliases.iter() {
+            if target
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .is_empty()
+            {
+                return Err(format!("`piper.aliases.{}`: the voice is empty", alias));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a TOML file, or a YAML one if its extension is `.yaml` or `.yml`.
+fn parse<T: DeserializeOwned>(path: &Path) -> Result<T, ServerError> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        ServerError::Operation(format!(
+            "Failed to read the config file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let is_yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    match is_yaml {
+        true => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        false => toml::from_str(&content).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| ServerError::Operation(format!("Invalid config file {}: {}", path.display(), e)))
+}
@@ -0,0 +1,254 @@
+//! Cross-origin resource sharing, applied to every route in one place.
+//!
+//! Preflight `OPTIONS` requests are answered before authentication, with the allowed methods and
+//! headers. Every response gets `Access-Control-Allow-Origin` if the origin of the request is
+//! allowed. By default any origin, method and header is allowed, without credentials.
+
+use crate::config::CorsConfig;
+use hyper::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    http::Method,
+    Body, Request, Response, Uri,
+};
+use once_cell::sync::OnceCell;
+use std::fmt;
+
+// the CORS policy, set at startup
+pub(crate) static CORS: OnceCell<Cors> = OnceCell::new();
+
+/// The CORS policy in use: the one set at startup, or one allowing everything.
+pub(crate) fn cors() -> &'static Cors {
+    CORS.get_or_init(Cors::default)
+}
+
+/// Origins, methods or headers allowed: any of them, given as `*`, or those listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Allowed<T> {
+    Any,
+    List(Vec<T>),
+}
+impl<T: fmt::Display> fmt::Display for Allowed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allowed::Any => write!(f, "*"),
+            Allowed::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, "{}", values.join(", "))
+            }
+        }
+    }
+}
+impl<T> Allowed<T> {
+    fn parse(
+        values: Option<&[String]>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Self, String> {
+        match values {
+            None => Ok(Allowed::Any),
+            Some(values) if values.iter().any(|value| value.trim() == "*") => Ok(Allowed::Any),
+            Some(values) => values
+                .iter()
+                .map(|value| parse(value.trim()))
+                .collect::<Result<_, _>>()
+                .map(Allowed::List),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Cors {
+    origins: Allowed<String>,
+    methods: Allowed<Method>,
+    headers: Allowed<HeaderName>,
+    allow_credentials: bool,
+    max_age_secs: Option<u64>,
+}
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: Allowed::Any,
+            methods: Allowed::Any,
+            headers: Allowed::Any,
+            allow_credentials: false,
+            max_age_secs: None,
+        }
+    }
+}
+impl TryFrom<&CorsConfig> for Cors {
+    type Error = String;
+
+    fn try_from(config: &CorsConfig) -> Result<Self, Self::Error> {
+        let origins = Allowed::parse(config.allowed_origins.as_deref(), parse_origin)
+            .map_err(|e| format!("`cors.allowed_origins`: {}", e))?;
+        let methods = Allowed::parse(config.allowed_methods.as_deref(), |method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("`{}` is not an HTTP method", method))
+        })
+        .map_err(|e| format!("`cors.allowed_methods`: {}", e))?;
+        let headers = Allowed::parse(config.allowed_headers.as_deref(), |header| {
+            header
+                .parse::<HeaderName>()
+                .map_err(|_| format!("`{}` is not a header name", header))
+        })
+        .map_err(|e| format!("`cors.allowed_headers`: {}", e))?;
+
+        // browsers reject credentials sent to any origin
+        if config.allow_credentials && origins == Allowed::Any {
+            return Err(
+                "`cors.allow_credentials` needs the origins to be listed in `cors.allowed_origins`, not `*`"
+                    .to_string(),
+            );
+        }
+
+        Ok(Cors {
+            origins,
+            methods,
+            headers,
+            allow_credentials: config.allow_credentials,
+            max_age_secs: config.max_age_secs,
+        })
+    }
+}
+impl Cors {
+    /// Answer a preflight request with the allowed methods and headers.
+    ///
+    /// `*` does not cover the `Authorization` header, nor any method or header when credentials are
+    /// allowed, so any is allowed by echoing the requested ones. A request from an origin that is not
+    /// allowed gets no CORS headers.
+    pub(crate) fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        if self.allowed_origin(req.headers().get(ORIGIN)).is_none() {
+            return response;
+        }
+        let headers = response.headers_mut();
+
+        let requested_method = req.headers().get(ACCESS_CONTROL_REQUEST_METHOD);
+        let methods = match (&self.methods, requested_method) {
+            (Allowed::Any, Some(method)) if self.allow_credentials => Some(method.clone()),
+            (Allowed::Any, _) => Some(HeaderValue::from_static("*")),
+            (Allowed::List(methods), _) => join(methods.iter().map(Method::as_str)),
+        };
+        if let Some(methods) = methods {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        let requested_headers = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS);
+        let allowed_headers = match (&self.headers, requested_headers) {
+            (Allowed::Any, Some(requested)) => Some(requested.clone()),
+            (Allowed::Any, None) => Some(HeaderValue::from_static("*")),
+            (Allowed::List(names), _) => join(names.iter().map(HeaderName::as_str)),
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+
+        if let Some(max_age_secs) = self.max_age_secs {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age_secs));
+        }
+
+        response
+    }
+
+    /// Set the CORS headers of a response to a request from `origin`.
+    pub(crate) fn set_headers(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        // the response depends on the origin, so caches must not share it across origins
+        if let Allowed::List(_) = self.origins {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+
+        let allowed_origin = match self.allowed_origin(origin) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        // preflight responses already name what they allow
+        if !headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS) {
+            let methods = match &self.methods {
+                Allowed::Any => Some(HeaderValue::from_static("*")),
+                Allowed::List(methods) => join(methods.iter().map(Method::as_str)),
+            };
+            if let Some(methods) = methods {
+                headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+            }
+        }
+        if !headers.contains_key(ACCESS_CONTROL_ALLOW_HEADERS) {
+            let names = match &self.headers {
+                Allowed::Any => Some(HeaderValue::from_static("*")),
+                Allowed::List(names) => join(names.iter().map(HeaderName::as_str)),
+            };
+            if let Some(names) = names {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, names);
+            }
+        }
+    }
+
+    // the `Access-Control-Allow-Origin` header for a request from `origin`, if it is allowed
+    fn allowed_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match &self.origins {
+            Allowed::Any => Some(HeaderValue::from_static("*")),
+            Allowed::List(origins) => origin
+                .filter(|origin| {
+                    origin.to_str().is_ok_and(|origin| {
+                        origins
+                            .iter()
+                            .any(|allowed| origin.eq_ignore_ascii_case(allowed))
+                    })
+                })
+                .cloned(),
+        }
+    }
+}
+impl fmt::Display for Cors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allowed_origins: {}, allowed_methods: {}, allowed_headers: {}, allow_credentials: {}",
+            self.origins, self.methods, self.headers, self.allow_credentials
+        )?;
+        if let Some(max_age_secs) = self.max_age_secs {
+            write!(f, ", max_age_secs: {}", max_age_secs)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The `Origin` header of a request.
+pub(crate) fn origin(req: &Request<Body>) -> Option<HeaderValue> {
+    req.headers().get(ORIGIN).cloned()
+}
+
+// an origin is a scheme and a host, with an optional port, e.g. `https://example.com:8443`
+fn parse_origin(origin: &str) -> Result<String, String> {
+    let err_msg = || format!("`{}` is not an origin like `https://example.com`", origin);
+
+    let uri = origin.parse::<Uri>().map_err(|_| err_msg())?;
+    if uri.scheme().is_none() || uri.authority().is_none() || origin.ends_with('/') {
+        return Err(err_msg());
+    }
+    if uri
+        .path_and_query()
+        .is_some_and(|path| !path.as_str().is_empty() && path.as_str() != "/")
+    {
+        return Err(err_msg());
+    }
+
+    Ok(origin.to_string())
+}
+
+fn join<'a>(values: impl Iterator<Item = &'a str>) -> Option<HeaderValue> {
+    HeaderValue::from_str(&values.collect::<Vec<_>>().join(", ")).ok()
+}
@@ -1,4 +1,7 @@
-use hyper::{Body, Response};
+use hyper::{
+    header::{ALLOW, RETRY_AFTER},
+    Body, Response, StatusCode,
+};
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -6,93 +9,239 @@ pub(crate) fn not_implemented() -> Response<Body> {
     // log error
     error!(target: "stdout", "501 Not Implemented");
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_IMPLEMENTED)
-        .body(Body::from("501 Not Implemented"))
-        .unwrap()
+    json_error(
+        StatusCode::NOT_IMPLEMENTED,
+        "server_error",
+        None,
+        "not_implemented",
+        "Not Implemented",
+    )
 }
 
 pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "500 Internal Server Error".to_string(),
-        false => format!("500 Internal Server Error: {}", msg.as_ref()),
-    };
-
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from(err_msg))
-        .unwrap()
+    ServerError::Operation(msg.as_ref().to_string()).into_response()
 }
 
 pub(crate) fn bad_request(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "400 Bad Request".to_string(),
-        false => format!("400 Bad Request: {}", msg.as_ref()),
-    };
-
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+    ServerError::BadRequest(msg.as_ref().to_string()).into_response()
+}
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::BAD_REQUEST)
-        .body(Body::from(err_msg))
-        .unwrap()
+/// `param` of the request has a value the server does not support.
+pub(crate) fn invalid_value(param: &'static str, msg: impl AsRef<str>) -> Response<Body> {
+    ServerError::InvalidValue {
+        param,
+        message: msg.as_ref().to_string(),
+    }
+    .into_response()
 }
 
-pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "401 Unauthorized".to_string(),
-        false => format!("401 Unauthorized: {}", msg.as_ref()),
-    };
+/// `param` of the request is well-formed, but leaves nothing the server can process.
+pub(crate) fn unprocessable_entity(param: &'static str, msg: impl AsRef<str>) -> Response<Body> {
+    ServerError::UnprocessableEntity {
+        param,
+        message: msg.as_ref().to_string(),
+    }
+    .into_response()
+}
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+// only the Piper routes look up resources by id
+#[cfg_attr(not(feature = "piper"), allow(dead_code))]
+pub(crate) fn not_found(msg: impl AsRef<str>) -> Response<Body> {
+    ServerError::NotFound(msg.as_ref().to_string()).into_response()
+}
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::UNAUTHORIZED)
-        .body(Body::from(err_msg))
-        .unwrap()
+/// The route only accepts the `allow` methods, e.g. `GET, DELETE`.
+pub(crate) fn method_not_allowed(method: &hyper::Method, allow: &str) -> Response<Body> {
+    ServerError::MethodNotAllowed {
+        message: format!(
+            "The `{}` method is not allowed. Allowed methods: {}",
+            method, allow
+        ),
+        allow: allow.to_string(),
+    }
+    .into_response()
 }
 
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
     let err_msg = match msg.as_ref().is_empty() {
-        true => "404 The requested service endpoint is not found".to_string(),
+        true => "The requested service endpoint is not found".to_string(),
         false => format!(
-            "404 The requested service endpoint is not found: {}",
+            "The requested service endpoint is not found: {}",
             msg.as_ref()
         ),
     };
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_FOUND)
-        .body(Body::from(err_msg))
-        .unwrap()
+    ServerError::NotFound(err_msg).into_response()
 }
 
+/// Errors of the server. Each variant maps to an HTTP status and an OpenAI error `type` and `code`.
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
 pub enum ServerError {
     /// Generic error returned while performing an operation
     #[error("{0}")]
     Operation(String),
+    /// The request cannot be parsed, e.g. invalid JSON or a missing field
+    #[error("{0}")]
+    BadRequest(String),
+    /// The API key is missing, malformed, unknown or revoked
+    #[error("{message}")]
+    Unauthorized { code: &'static str, message: String },
+    /// The API key is not allowed to do what the request asks
+    #[error("{message}")]
+    Forbidden { code: &'static str, message: String },
+    /// The route or the requested resource does not exist
+    #[error("{0}")]
+    NotFound(String),
+    /// The route does not accept the method of the request
+    #[error("{message}")]
+    MethodNotAllowed { message: String, allow: String },
+    /// The request body is too large
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    /// A field of the request, e.g. the speech input, is too long
+    #[error("{message}")]
+    TooLong {
+        param: &'static str,
+        message: String,
+    },
+    /// A field of the request has an unsupported value
+    #[error("{message}")]
+    InvalidValue {
+        param: &'static str,
+        message: String,
+    },
+    /// A field of the request is well-formed but cannot be processed, e.g. an input with nothing to
+    /// synthesize. Unsupported values stay `400 Bad Request`, as in the OpenAI API.
+    #[error("{message}")]
+    UnprocessableEntity {
+        param: &'static str,
+        message: String,
+    },
+    /// The client sends too many requests
+    #[error("{message}")]
+    TooManyRequests { message: String, retry_after: u64 },
+    /// The quota of the API key is used up
+    #[error("{message}")]
+    QuotaExceeded { message: String, retry_after: u64 },
+    /// The server cannot take the request right now
+    #[error("{message}")]
+    Unavailable {
+        message: String,
+        retry_after: Option<u64>,
+    },
+}
+impl ServerError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ServerError::Operation(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServerError::BadRequest(_)
+            | ServerError::TooLong { .. }
+            | ServerError::InvalidValue { .. } => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ServerError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            ServerError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ServerError::TooManyRequests { .. } | ServerError::QuotaExceeded { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ServerError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// The OpenAI error `type`.
+    fn error_type(&self) -> &'static str {
+        match self {
+            ServerError::Operation(_) | ServerError::Unavailable { .. } => "server_error",
+            ServerError::Unauthorized { .. } => "authentication_error",
+            ServerError::Forbidden { .. } => "permission_error",
+            ServerError::TooManyRequests { .. } => "rate_limit_error",
+            ServerError::QuotaExceeded { .. } => "insufficient_quota",
+            _ => "invalid_request_error",
+        }
+    }
+
+    /// A machine-readable error code.
+    fn code(&self) -> &'static str {
+        match self {
+            ServerError::Operation(_) => "internal_error",
+            ServerError::BadRequest(_) => "invalid_request",
+            ServerError::Unauthorized { code, .. } | ServerError::Forbidden { code, .. } => code,
+            ServerError::NotFound(_) => "not_found",
+            ServerError::MethodNotAllowed { .. } => "method_not_allowed",
+            ServerError::PayloadTooLarge(_) => "payload_too_large",
+            ServerError::TooLong { .. } => "string_above_max_length",
+            ServerError::InvalidValue { .. } => "invalid_value",
+            ServerError::UnprocessableEntity { .. } => "unprocessable_entity",
+            ServerError::TooManyRequests { .. } => "rate_limit_exceeded",
+            ServerError::QuotaExceeded { .. } => "daily_quota_exceeded",
+            ServerError::Unavailable { .. } => "service_unavailable",
+        }
+    }
+
+    /// Build the JSON error response: `{"error": {"message", "type", "param", "code"}}`.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        let status = self.status();
+
+        // log error
+        error!(target: "stdout", "{} {}", status, self);
+
+        let param = match &self {
+            ServerError::InvalidValue { param, .. }
+            | ServerError::TooLong { param, .. }
+            | ServerError::UnprocessableEntity { param, .. } => Some(*param),
+            _ => None,
+        };
+        let mut response = json_error(
+            status,
+            self.error_type(),
+            param,
+            self.code(),
+            self.to_string(),
+        );
+
+        let headers = response.headers_mut();
+        match self {
+            ServerError::MethodNotAllowed { allow, .. } => {
+                if let Ok(allow) = allow.parse() {
+                    headers.insert(ALLOW, allow);
+                }
+            }
+            ServerError::TooManyRequests { retry_after, .. }
+            | ServerError::QuotaExceeded { retry_after, .. }
+            | ServerError::Unavailable {
+                retry_after: Some(retry_after),
+                ..
+            } => {
+                headers.insert(RETRY_AFTER, retry_after.into());
+            }
+            _ => {}
+        }
+
+        response
+    }
+}
+
+fn json_error(
+    status: StatusCode,
+    error_type: &str,
+    param: Option<&str>,
+    code: &str,
+    msg: impl AsRef<str>,
+) -> Response<Body> {
+    let body = serde_json::json!({
+        "error": {
+            "message": msg.as_ref(),
+            "type": error_type,
+            "param": param,
+            "code": code,
+        }
+    });
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(body.to_string()))
+        .unwrap()
 }
@@ -0,0 +1,189 @@
+//! Probes for orchestrators and fleet inventory: liveness, readiness and version.
+
+use crate::{
+    backend::{TtsBackend, BACKENDS},
+    error, queue, shutdown,
+};
+use hyper::{http::Method, Body, Request, Response, StatusCode};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// readiness is checked at most this often, so that frequent probes do not keep the voices busy
+const READY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// cargo features the server can be built with
+const FEATURES: [(&str, bool); 6] = [
+    ("piper", cfg!(feature = "piper")),
+    ("gpt_sovits", cfg!(feature = "gpt_sovits")),
+    ("mp3", cfg!(feature = "mp3")),
+    ("flac", cfg!(feature = "flac")),
+    ("opus", cfg!(feature = "opus")),
+    ("aac", cfg!(feature = "aac")),
+];
+
+// the last readiness check: when it ran, whether every voice was ready, and the report. Held while
+// a check runs, so that probes arriving meanwhile wait for its result.
+static LAST_READY_CHECK: Mutex<Option<(Instant, bool, serde_json::Value)>> = Mutex::const_new(None);
+
+/// Tell that the server answers requests.
+///
+/// - `GET /health`
+pub(crate) async fn health_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    json_response(StatusCode::OK, serde_json::json!({ "status": "ok" }))
+}
+
+/// Tell whether every voice of every backend can synthesize a short phrase. Answers `503 Service
+/// Unavailable` if one cannot, or if the server is shutting down.
+///
+/// The phrase is synthesized in a slot of the synthesis queue, like a speech request.
+///
+/// - `GET /ready`
+pub(crate) async fn ready_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    // no new requests should be sent while the requests in flight drain
+    if shutdown::shutdown().is_draining() {
+        return json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::json!({ "status": "draining" }),
+        );
+    }
+
+    let (ready, report) = check_readiness().await;
+    if !ready {
+        // log
+        error!(target: "stdout", "The server is not ready: {}", report);
+    }
+
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    json_response(status, report)
+}
+
+async fn check_readiness() -> (bool, serde_json::Value) {
+    let mut last_check = LAST_READY_CHECK.lock().await;
+    if let Some((checked, ready, report)) = last_check.as_ref() {
+        if checked.elapsed() < READY_CHECK_INTERVAL {
+            return (*ready, report.clone());
+        }
+    }
+
+    let _permit = match queue::synthesis_queue().acquire().await {
+        Ok(permit) => permit,
+        // a busy server reports its last check rather than waiting longer for a slot
+        Err(e) => {
+            return match last_check.as_ref() {
+                Some((_, ready, report)) => (*ready, report.clone()),
+                None => (
+                    false,
+                    serde_json::json!({ "status": "not_ready", "error": e.to_string() }),
+                ),
+            }
+        }
+    };
+
+    let backends: Vec<&dyn TtsBackend> = BACKENDS
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|backend| backend.as_ref())
+        .collect();
+
+    let mut ready = !backends.is_empty();
+    let backends: Vec<serde_json::Value> = backends
+        .into_iter()
+        .map(|backend| {
+            let voices: Vec<serde_json::Value> = backend
+                .canary()
+                .into_iter()
+                .map(|(voice, result)| match result {
+                    Ok(()) => serde_json::json!({ "name": voice, "ready": true }),
+                    Err(err_msg) => {
+                        serde_json::json!({ "name": voice, "ready": false, "error": err_msg })
+                    }
+                })
+                .collect();
+            let backend_ready = voices.iter().all(|voice| voice["ready"] == true);
+            ready &= backend_ready;
+
+            serde_json::json!({
+                "name": backend.name(),
+                "ready": backend_ready,
+                "voices": voices,
+            })
+        })
+        .collect();
+
+    let report = serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "backends": backends,
+    });
+    *last_check = Some((Instant::now(), ready, report.clone()));
+
+    (ready, report)
+}
+
+/// Report the version of the server, its enabled cargo features and the loaded models.
+///
+/// - `GET /version`
+pub(crate) async fn version_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| *feature)
+        .collect();
+    let backends: Vec<serde_json::Value> = BACKENDS
+        .get()
+        .into_iter()
+        .flatten()
+        .map(|backend| {
+            serde_json::json!({
+                "name": backend.name(),
+                "models": backend.model_info(),
+            })
+        })
+        .collect();
+
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "features": features,
+            "backends": backends,
+        }),
+    )
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .status(status)
+        .body(Body::from(value.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Limits on the size of the requests, checked before anything is synthesized.
+
+use crate::error::ServerError;
+use hyper::{body::HttpBody, header::CONTENT_LENGTH, Body, Request};
+use once_cell::sync::OnceCell;
+
+/// Default maximum size of a request body: 1 MiB.
+pub(crate) const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Default maximum number of characters of a speech input, as in the OpenAI API.
+pub(crate) const DEFAULT_MAX_INPUT_CHARS: usize = 4096;
+/// Default maximum estimated duration of the audio of a speech input: 10 minutes.
+pub(crate) const DEFAULT_MAX_AUDIO_SECONDS: f64 = 600.0;
+
+// characters read per second at the usual speaking rate of about 150 words per minute
+const CHARS_PER_SECOND: f64 = 15.0;
+
+// request limits, set at startup
+pub(crate) static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+/// The limits in use: those set at startup, or the defaults.
+pub(crate) fn limits() -> &'static Limits {
+    LIMITS.get_or_init(Limits::default)
+}
+
+/// Request limits. `0` disables a limit.
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    pub(crate) max_body_bytes: usize,
+    pub(crate) max_input_chars: usize,
+    pub(crate) max_audio_seconds: f64,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_input_chars: DEFAULT_MAX_INPUT_CHARS,
+            max_audio_seconds: DEFAULT_MAX_AUDIO_SECONDS,
+        }
+    }
+}
+impl Limits {
+    /// Read the body of a request, failing as soon as it exceeds `max_body_bytes`.
+    pub(crate) async fn read_body(&self, req: Request<Body>) -> Result<Vec<u8>, ServerError> {
+        let too_large = || {
+            ServerError::PayloadTooLarge(format!(
+                "The request body exceeds the limit of {} bytes.",
+                self.max_body_bytes
+            ))
+        };
+
+        // most clients announce the size, so that the body does not even have to be read
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if self.max_body_bytes > 0
+            && content_length.unwrap_or_default() > self.max_body_bytes as u64
+        {
+            return Err(too_large());
+        }
+
+        let mut body = req.into_body();
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| {
+                ServerError::BadRequest(format!("Fail to read buffer from request body. {}", e))
+            })?;
+            if self.max_body_bytes > 0 && buf.len() + chunk.len() > self.max_body_bytes {
+                return Err(too_large());
+            }
+            buf.extend_from_slice(&chunk);
+        }
+
+        Ok(buf)
+    }
+
+    /// Check the length of a speech input and the estimated duration of its audio.
+    pub(crate) fn check_input(&self, input: &str) -> Result<(), ServerError> {
+        let chars = input.chars().count();
+        if self.max_input_chars > 0 && chars > self.max_input_chars {
+            return Err(ServerError::TooLong {
+                param: "input",
+                message: format!(
+                    "The input has {} characters, more than the limit of {}.",
+                    chars, self.max_input_chars
+                ),
+            });
+        }
+
+        let seconds = chars as f64 / CHARS_PER_SECOND;
+        if self.max_audio_seconds > 0.0 && seconds > self.max_audio_seconds {
+            return Err(ServerError::TooLong {
+                param: "input",
+                message: format!(
+                    "The audio of the input would last about {:.0} seconds, more than the limit of {} seconds.",
+                    seconds, self.max_audio_seconds
+                ),
+            });
+        }
+
+        Ok(())
+    }
+}
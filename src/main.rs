@@ -1,121 +1,468 @@
 #[macro_use]
 extern crate log;
 
+mod access_log;
+mod audio;
+mod auth;
 mod backend;
+#[cfg(feature = "piper")]
+mod cache;
+mod config;
+mod cors;
 mod error;
+mod health;
+mod limits;
+mod metrics;
+mod queue;
+mod rate_limit;
+mod shutdown;
+mod text;
+mod timing;
 
 use anyhow::Result;
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Parser, ValueEnum};
+use config::Config;
 use error::ServerError;
 use hyper::{
-    body::HttpBody,
+    http::Method,
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server,
 };
-#[cfg(feature = "piper")]
-use llama_core::metadata::piper::PiperMetadata;
-use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use tokio::net::TcpListener;
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 // default port
-const DEFAULT_PORT: &str = "8080";
-
-// API key
-pub(crate) static LLAMA_API_KEY: OnceCell<String> = OnceCell::new();
+const DEFAULT_PORT: u16 = 8080;
 
 #[derive(Debug, Parser)]
-#[command(name = "Whisper API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "Whisper API Server")]
+#[command(name = "TTS API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "OpenAI-compatible text-to-speech API server")]
 #[command(group = ArgGroup::new("socket_address_group").multiple(false).args(&["socket_addr", "port"]))]
 struct Cli {
-    /// Model name.
-    #[arg(short, long, required = true)]
-    model_name: String,
-    /// Path to the whisper model file
+    /// Path to a TOML or YAML configuration file. Command line arguments override its settings.
     #[arg(long)]
-    model: PathBuf,
+    config_file: Option<PathBuf>,
+    /// TTS backends to serve, comma separated or repeated [default: all compiled-in engines]
+    #[arg(
+        long = "backend",
+        value_name = "BACKEND",
+        value_enum,
+        value_delimiter = ','
+    )]
+    backends: Vec<Backend>,
+    /// Socket address of the TTS API Server instance. For example, `0.0.0.0:8080`.
+    #[arg(long, default_value = None, value_parser = clap::value_parser!(SocketAddr), group = "socket_address_group")]
+    socket_addr: Option<SocketAddr>,
+    /// Port number [default: 8080]
+    #[arg(long, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
+    port: Option<u16>,
+    /// Format of the access log records [default: text]
+    #[arg(long, value_enum)]
+    access_log_format: Option<access_log::AccessLogFormat>,
+    #[cfg(feature = "piper")]
+    #[command(flatten)]
+    piper: PiperArgs,
+    #[cfg(feature = "gpt_sovits")]
+    #[command(flatten)]
+    gpt_sovits: GptSovitsArgs,
+}
+
+/// Arguments of the piper backend.
+#[cfg(feature = "piper")]
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Piper backend")]
+struct PiperArgs {
+    /// Model name. Names the voice given by `--model`, and defaults to the file stem of the model.
+    #[arg(short, long)]
+    model_name: Option<String>,
+    /// Path to the Piper voice model file
+    #[arg(long, requires = "config")]
+    model: Option<PathBuf>,
     /// Path to the voice config file
-    #[arg(long)]
-    config: PathBuf,
+    #[arg(long, requires = "model")]
+    config: Option<PathBuf>,
+    /// Additional voice, in the format of `NAME=MODEL[,CONFIG]`. The config defaults to `MODEL.json`. Can be repeated.
+    #[arg(long = "voice", value_name = "NAME=MODEL[,CONFIG]")]
+    voices: Vec<backend::voices::VoiceSpec>,
+    /// Voice alias, in the format of `ALIAS=VOICE[:SPEAKER]`, e.g. `alloy=en_US-libritts:p3922`. SPEAKER is a speaker id or a speaker name of the voice config. Can be repeated.
+    #[arg(long = "voice-alias", value_name = "ALIAS=VOICE[:SPEAKER]")]
+    voice_aliases: Vec<backend::voices::VoiceAlias>,
     /// Path to the espeak-ng data directory
     #[arg(long)]
-    espeak_ng_dir: PathBuf,
-    /// Socket address of LlamaEdge API Server instance. For example, `0.0.0.0:8080`.
-    #[arg(long, default_value = None, value_parser = clap::value_parser!(SocketAddr), group = "socket_address_group")]
-    socket_addr: Option<SocketAddr>,
-    /// Port number
-    #[arg(long, default_value = DEFAULT_PORT, value_parser = clap::value_parser!(u16), group = "socket_address_group")]
-    port: u16,
+    espeak_ng_dir: Option<PathBuf>,
+}
+/// Arguments of the GPT-SoVITS backend.
+#[cfg(feature = "gpt_sovits")]
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "GPT-SoVITS backend")]
+struct GptSovitsArgs {
+    /// Model name that selects GPT-SoVITS on `/v1/audio/speech` [default: gpt-sovits]
+    #[arg(long)]
+    gpt_sovits_model_name: Option<String>,
+}
+
+#[cfg(feature = "piper")]
+impl PiperArgs {
+    /// The first piper argument given on the command line, if any.
+    fn first_given(&self) -> Option<&'static str> {
+        if self.model_name.is_some() {
+            Some("--model-name")
+        } else if self.model.is_some() {
+            Some("--model")
+        } else if self.config.is_some() {
+            Some("--config")
+        } else if !self.voices.is_empty() {
+            Some("--voice")
+        } else if !self.voice_aliases.is_empty() {
+            Some("--voice-alias")
+        } else if self.espeak_ng_dir.is_some() {
+            Some("--espeak-ng-dir")
+        } else {
+            None
+        }
+    }
 }
 
 #[allow(clippy::needless_return)]
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), ServerError> {
-    // get the environment variable `LLAMA_LOG`
+    // set global logger
+    #[cfg(target_os = "wasi")]
+    wasi_logger::Logger::install().expect("failed to install wasi_logger::Logger");
+    #[cfg(not(target_os = "wasi"))]
+    log::set_logger(&StdoutLogger).expect("failed to install the stdout logger");
+    log::set_max_level(LogLevel::Info.into());
+
+    // parse the command line arguments
+    let cli = Cli::parse();
+
+    // load the config file
+    let config = match cli.config_file.as_ref() {
+        Some(path) => {
+            let config = Config::load(path).inspect_err(|e| {
+                error!(target: "stdout", "{}", e);
+            })?;
+
+            info!(target: "stdout", "config file: {}", path.display());
+
+            config
+        }
+        None => Config::default(),
+    };
+
+    // get the environment variable `LLAMA_LOG`, which overrides the log level of the config file
     let rust_log = std::env::var("LLAMA_LOG")
         .unwrap_or_default()
         .to_lowercase();
     let (_, log_level) = match rust_log.is_empty() {
-        true => ("stdout", LogLevel::Info),
+        true => ("stdout", config.logging.level.unwrap_or(LogLevel::Info)),
         false => match rust_log.split_once("=") {
             Some((target, level)) => (target, level.parse().unwrap_or(LogLevel::Info)),
             None => ("stdout", rust_log.parse().unwrap_or(LogLevel::Info)),
         },
     };
-
-    // set global logger
-    wasi_logger::Logger::install().expect("failed to install wasi_logger::Logger");
     log::set_max_level(log_level.into());
 
     info!(target: "stdout", "log_level: {}", log_level);
 
-    if let Ok(api_key) = std::env::var("API_KEY") {
-        // define a const variable for the API key
-        if let Err(e) = LLAMA_API_KEY.set(api_key) {
-            let err_msg = format!("Failed to set API key. {}", e);
+    info!(target: "stdout", "timing_headers: {}", config.server.timing_headers);
+    if timing::TIMING_HEADERS
+        .set(config.server.timing_headers)
+        .is_err()
+    {
+        let err_msg = "Failed to set the timing headers.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    // access log format: the command line overrides the config file
+    let access_log_format = cli
+        .access_log_format
+        .or(config.logging.access_log_format)
+        .unwrap_or_default();
+    info!(target: "stdout", "access_log_format: {}", access_log_format);
+    if access_log::ACCESS_LOG_FORMAT
+        .set(access_log_format)
+        .is_err()
+    {
+        let err_msg = "Failed to set the access log format.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    // the environment variable `API_KEY` overrides the API key of the config file
+    let mut keys = Vec::new();
+    let api_key = std::env::var("API_KEY").ok().or(config.auth.api_key);
+    let key_hash = match (api_key, config.auth.api_key_sha256) {
+        (Some(api_key), _) => Some(auth::hash_key(&api_key)),
+        (None, Some(api_key_sha256)) => {
+            Some(auth::parse_key_hash(&api_key_sha256).map_err(|e| {
+                let err_msg = format!("Invalid API key digest: {}", e);
+
+                error!(target: "stdout", "{}", err_msg);
+
+                ServerError::Operation(err_msg)
+            })?)
+        }
+        (None, None) => None,
+    };
+    if let Some(key_hash) = key_hash {
+        keys.push(auth::ApiKey::unrestricted("default", key_hash));
+    }
+    for key_config in config.auth.keys {
+        let name = key_config.name.clone();
+        let api_key = auth::ApiKey::try_from(key_config).map_err(|e| {
+            let err_msg = format!("Invalid API key `{}`: {}", name, e);
+
+            error!(target: "stdout", "{}", err_msg);
+
+            ServerError::Operation(err_msg)
+        })?;
+        keys.push(api_key);
+    }
+    let public_routes = match config.auth.public_routes {
+        Some(public_routes) => public_routes,
+        None => auth::DEFAULT_PUBLIC_ROUTES
+            .iter()
+            .map(|route| route.to_string())
+            .collect(),
+    };
+    let has_api_keys = !keys.is_empty();
+    if has_api_keys {
+        let names: Vec<&str> = keys.iter().map(|api_key| api_key.name.as_str()).collect();
+        info!(target: "stdout", "API key authentication enabled, keys: {}, public routes: {}", names.join(", "), public_routes.join(", "));
+    }
+    let public_routes = public_routes
+        .iter()
+        .map(|route| route.parse())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            let err_msg = format!("Invalid public route {}", e);
+
+            error!(target: "stdout", "{}", err_msg);
+
+            ServerError::Operation(err_msg)
+        })?;
+    if let Err(e) = auth::AUTH.set(auth::Auth::new(keys, public_routes)) {
+        let err_msg = format!("Failed to set the authentication settings. {:?}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    let limits = limits::Limits {
+        max_body_bytes: config
+            .limits
+            .max_body_bytes
+            .unwrap_or(limits::DEFAULT_MAX_BODY_BYTES),
+        max_input_chars: config
+            .limits
+            .max_input_chars
+            .unwrap_or(limits::DEFAULT_MAX_INPUT_CHARS),
+        max_audio_seconds: config
+            .limits
+            .max_audio_seconds
+            .unwrap_or(limits::DEFAULT_MAX_AUDIO_SECONDS),
+    };
+    info!(target: "stdout", "limits: max_body_bytes: {}, max_input_chars: {}, max_audio_seconds: {}", limits.max_body_bytes, limits.max_input_chars, limits.max_audio_seconds);
+    if let Err(e) = limits::LIMITS.set(limits) {
+        let err_msg = format!("Failed to set the request limits. {:?}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    let max_concurrent = config
+        .queue
+        .max_concurrent
+        .unwrap_or(queue::DEFAULT_MAX_CONCURRENT);
+    let max_queued = config.queue.max_queued.unwrap_or(queue::DEFAULT_MAX_QUEUED);
+    let timeout_secs = config
+        .queue
+        .timeout_secs
+        .unwrap_or(queue::DEFAULT_QUEUE_TIMEOUT_SECS);
+    info!(target: "stdout", "synthesis queue: max_concurrent: {}, max_queued: {}, timeout_secs: {}", max_concurrent, max_queued, timeout_secs);
+    let synthesis_queue = queue::SynthesisQueue::new(
+        max_concurrent,
+        max_queued,
+        Duration::from_secs_f64(timeout_secs),
+    );
+    if let Err(e) = queue::SYNTHESIS_QUEUE.set(synthesis_queue) {
+        let err_msg = format!("Failed to set the synthesis queue. {:?}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    let requests_per_minute = config.rate_limit.requests_per_minute.unwrap_or_default();
+    let chars_per_minute = config.rate_limit.chars_per_minute.unwrap_or_default();
+    info!(target: "stdout", "rate limit: requests_per_minute: {}, chars_per_minute: {}", requests_per_minute, chars_per_minute);
+    let rate_limiter = rate_limit::RateLimiter::new(requests_per_minute, chars_per_minute);
+    if let Err(e) = rate_limit::RATE_LIMITER.set(rate_limiter) {
+        let err_msg = format!("Failed to set the rate limits. {:?}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    let shutdown_timeout_secs = config
+        .server
+        .shutdown_timeout_secs
+        .unwrap_or(shutdown::DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+    info!(target: "stdout", "shutdown_timeout_secs: {}", shutdown_timeout_secs);
+    let shutdown_timeout = Duration::from_secs_f64(shutdown_timeout_secs);
+
+    info!(target: "stdout", "shutdown_route: {}", config.server.shutdown_route);
+    if config.server.shutdown_route && !has_api_keys {
+        warn!(target: "stdout", "`POST /shutdown` is served without API keys, so any client can shut the server down");
+    }
+    if cfg!(target_os = "wasi") && !config.server.shutdown_route {
+        warn!(target: "stdout", "WASI has no signals: the server cannot drain the requests in flight before it is stopped unless `shutdown_route` is enabled in the `[server]` section");
+    }
+    if shutdown::SHUTDOWN_ROUTE
+        .set(config.server.shutdown_route)
+        .is_err()
+    {
+        let err_msg = "Failed to set the shutdown route.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    let cors = cors::Cors::try_from(&config.cors).map_err(|e| {
+        let err_msg = format!("Invalid CORS settings: {}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        ServerError::Operation(err_msg)
+    })?;
+    info!(target: "stdout", "CORS: {}", cors);
+    if let Err(e) = cors::CORS.set(cors) {
+        let err_msg = format!("Failed to set the CORS policy. {:?}", e);
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    // log the version of the server
+    info!(target: "stdout", "TTS API Server v{}", env!("CARGO_PKG_VERSION"));
+
+    // the command line overrides the backends of the config file
+    let backends = match (cli.backends.is_empty(), config.backends.is_empty()) {
+        (false, _) => cli.backends,
+        (true, false) => config.backends,
+        (true, true) => Backend::defaults(),
+    };
+    let mut served: Vec<Backend> = Vec::new();
+    for backend in backends {
+        if !served.contains(&backend) {
+            served.push(backend);
+        }
+    }
+
+    // log backends
+    info!(target: "stdout", "backends: {}", served.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", "));
+
+    #[cfg(feature = "piper")]
+    if !served.contains(&Backend::Piper) {
+        if let Some(arg) = cli.piper.first_given() {
+            let err_msg = format!(
+                "`{}` only applies to the piper backend, which is not enabled by `--backend`.",
+                arg
+            );
 
             error!(target: "stdout", "{}", err_msg);
 
             return Err(ServerError::Operation(err_msg));
         }
     }
+    #[cfg(feature = "gpt_sovits")]
+    if !served.contains(&Backend::GptSovits) && cli.gpt_sovits.gpt_sovits_model_name.is_some() {
+        let err_msg = "`--gpt-sovits-model-name` only applies to the gpt-sovits backend, which is not enabled by `--backend`.";
 
-    // parse the command line arguments
-    let cli = Cli::parse();
+        error!(target: "stdout", "{}", err_msg);
 
-    // log the version of the server
-    info!(target: "stdout", "Whisper API Server v{}", env!("CARGO_PKG_VERSION"));
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
 
+    // the settings of each backend, taken by its initialization
     #[cfg(feature = "piper")]
-    {
-        // log model name
-        info!(target: "stdout", "model name: {}", &cli.model_name);
+    let mut piper_settings = Some((cli.piper, config.piper, config.cache));
+    #[cfg(feature = "gpt_sovits")]
+    let mut gpt_sovits_settings = Some((cli.gpt_sovits, config.gpt_sovits));
+
+    let mut tts_backends: Vec<Box<dyn backend::TtsBackend>> = Vec::new();
+    for backend in served {
+        match backend {
+            #[cfg(feature = "piper")]
+            Backend::Piper => {
+                if let Some((args, piper_config, cache_config)) = piper_settings.take() {
+                    let voices = init_piper(args, piper_config, cache_config)?;
+                    tts_backends.push(Box::new(backend::piper::PiperBackend::new(voices)));
+                }
+            }
+            #[cfg(feature = "gpt_sovits")]
+            Backend::GptSovits => {
+                if let Some((args, gpt_sovits_config)) = gpt_sovits_settings.take() {
+                    let model = init_gpt_sovits(args, gpt_sovits_config)?;
+                    tts_backends.push(Box::new(backend::gpt_sovits::GptSovitsBackend::new(model)));
+                }
+            }
+            Backend::Mock => tts_backends.push(Box::new(backend::mock::MockBackend::new())),
+        }
+    }
 
-        // log model path
-        info!(target: "stdout", "model path: {}", cli.model.display());
+    // a model name served by two backends would only ever reach the first one
+    let mut models: Vec<String> = Vec::new();
+    for tts_backend in tts_backends.iter() {
+        for model in tts_backend.models() {
+            if models.contains(&model) {
+                let err_msg = format!(
+                    "The model name `{}` is served by more than one backend.",
+                    model
+                );
 
-        // log voice config path
-        info!(target: "stdout", "voice config path: {}", cli.config.display());
+                error!(target: "stdout", "{}", err_msg);
 
-        // log espeak-ng data directory
-        info!(target: "stdout", "espeak-ng data directory: {}", cli.espeak_ng_dir.display());
+                return Err(ServerError::Operation(err_msg));
+            }
+            models.push(model);
+        }
+    }
+
+    if backend::BACKENDS.set(tts_backends).is_err() {
+        let err_msg = "Failed to set the TTS backends.";
 
-        // create a default metadata
-        let metadata = PiperMetadata::default();
+        error!(target: "stdout", "{}", err_msg);
 
-        // init the piper context
-        llama_core::init_piper_context(&metadata, cli.model, cli.config, cli.espeak_ng_dir)
-            .map_err(|e| ServerError::Operation(e.to_string()))?;
+        return Err(ServerError::Operation(err_msg.to_string()));
     }
 
-    // socket address
-    let addr = match cli.socket_addr {
-        Some(addr) => addr,
-        None => SocketAddr::from(([0, 0, 0, 0], cli.port)),
+    // socket address: the command line overrides the config file
+    let addr = match (cli.socket_addr, cli.port) {
+        (Some(addr), _) => addr,
+        (None, Some(port)) => SocketAddr::from(([0, 0, 0, 0], port)),
+        (None, None) => match (config.server.socket_addr, config.server.port) {
+            (Some(addr), _) => addr,
+            (None, port) => SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(DEFAULT_PORT))),
+        },
     };
 
     let new_service = make_service_fn(move |conn: &AddrStream| {
@@ -126,7 +473,27 @@ async fn main() -> Result<(), ServerError> {
             conn.local_addr().to_string()
         );
 
-        async move { Ok::<_, Error>(service_fn(handle_request)) }
+        // requests without an API key are rate limited by remote address
+        let remote_addr = conn.remote_addr();
+        async move {
+            Ok::<_, Error>(service_fn(move |mut req: Request<Body>| async move {
+                req.extensions_mut().insert(remote_addr);
+                let request_id = access_log::RequestId::of(&req);
+                req.extensions_mut().insert(request_id);
+                let access_record = access_log::AccessRecord::start(&req);
+                let origin = cors::origin(&req);
+
+                // count the request by route and status
+                let start = Instant::now();
+                let route = metrics::route(req.uri().path());
+                let mut response = handle_request(req).await?;
+                metrics::metrics().record_request(route, response.status(), start.elapsed());
+
+                cors::cors().set_headers(origin.as_ref(), response.headers_mut());
+
+                Ok::<_, hyper::Error>(access_record.finish(response))
+            }))
+        }
     });
 
     let tcp_listener = TcpListener::bind(addr).await.unwrap();
@@ -134,15 +501,150 @@ async fn main() -> Result<(), ServerError> {
 
     let server = Server::from_tcp(tcp_listener.into_std().unwrap())
         .unwrap()
-        .serve(new_service);
+        .serve(new_service)
+        .with_graceful_shutdown(shutdown::shutdown().signal());
 
-    match server.await {
+    match shutdown::drain(server, shutdown_timeout).await {
         Ok(_) => Ok(()),
         Err(e) => Err(ServerError::Operation(e.to_string())),
     }
 }
 
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+#[cfg(feature = "piper")]
+fn init_piper(
+    args: PiperArgs,
+    piper_config: config::PiperConfig,
+    cache_config: config::CacheConfig,
+) -> Result<&'static backend::voices::VoiceRegistry, ServerError> {
+    let espeak_ng_dir = match args.espeak_ng_dir.or(piper_config.espeak_ng_dir) {
+        Some(espeak_ng_dir) => espeak_ng_dir,
+        None => {
+            let err_msg = "The espeak-ng data directory is not set. Use `--espeak-ng-dir` or `piper.espeak_ng_dir` in the config file.";
+
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    };
+
+    // log espeak-ng data directory
+    info!(target: "stdout", "espeak-ng data directory: {}", espeak_ng_dir.display());
+
+    // voices of the config file, replaced by command line voices of the same name
+    let mut voices: Vec<backend::voices::VoiceSpec> = piper_config
+        .voices
+        .into_iter()
+        .map(|voice| {
+            let config = voice.config.unwrap_or_else(|| {
+                let mut config = voice.model.clone().into_os_string();
+                config.push(".json");
+                PathBuf::from(config)
+            });
+            backend::voices::VoiceSpec {
+                name: voice.name,
+                model: voice.model,
+                config,
+            }
+        })
+        .collect();
+    let mut cli_voices = Vec::new();
+    if let (Some(model), Some(voice_config)) = (args.model, args.config) {
+        // the voice given by `--model` and `--config` is named after `--model-name`
+        let name = match args.model_name {
+            Some(name) => name,
+            None => model
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+
+        // log model name
+        info!(target: "stdout", "model name: {}", &name);
+
+        cli_voices.push(backend::voices::VoiceSpec {
+            name,
+            model,
+            config: voice_config,
+        });
+    }
+    cli_voices.extend(args.voices);
+    voices.retain(|voice| !cli_voices.iter().any(|v| v.name == voice.name));
+    voices.extend(cli_voices);
+
+    // aliases of the config file, replaced by command line aliases of the same name
+    let mut aliases = Vec::new();
+    for (alias, target) in piper_config.aliases {
+        let alias = format!("{}={}", alias, target)
+            .parse::<backend::voices::VoiceAlias>()
+            .map_err(ServerError::Operation)?;
+        aliases.push(alias);
+    }
+    aliases.retain(|alias| !args.voice_aliases.iter().any(|a| a.name == alias.name));
+    aliases.extend(args.voice_aliases);
+
+    // load the piper voices
+    let registry = backend::voices::VoiceRegistry::load(&voices, &aliases, &espeak_ng_dir)?;
+    info!(target: "stdout", "voices: {}", registry.names().join(", "));
+    if backend::voices::PIPER_VOICES.set(registry).is_err() {
+        let err_msg = "Failed to set the voice registry.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    // set up the speech cache
+    if cache_config.max_entries > 0 {
+        info!(target: "stdout", "speech cache: {} entries, {} bytes", cache_config.max_entries, cache_config.max_bytes);
+
+        let speech_cache =
+            cache::SpeechCache::new(cache_config.max_entries, cache_config.max_bytes);
+        if cache::SPEECH_CACHE.set(speech_cache).is_err() {
+            let err_msg = "Failed to set the speech cache.";
+
+            error!(target: "stdout", "{}", err_msg);
+
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    }
+
+    backend::voices::PIPER_VOICES
+        .get()
+        .ok_or_else(|| ServerError::Operation("Failed to get the voice registry.".to_string()))
+}
+
+#[cfg(feature = "gpt_sovits")]
+fn init_gpt_sovits(
+    args: GptSovitsArgs,
+    gpt_sovits_config: config::GptSovitsConfig,
+) -> Result<&'static backend::gpt_sovits::GptSovitsModel, ServerError> {
+    let model_name = args
+        .gpt_sovits_model_name
+        .or(gpt_sovits_config.model_name)
+        .unwrap_or_else(|| "gpt-sovits".to_string());
+
+    // log model name
+    info!(target: "stdout", "gpt-sovits model name: {}", &model_name);
+
+    let gpt_sovits_model =
+        backend::gpt_sovits::GptSovitsModel::new(model_name, gpt_sovits_config.canary_speaker);
+    if backend::gpt_sovits::GPT_SOVITS_MODEL
+        .set(gpt_sovits_model)
+        .is_err()
+    {
+        let err_msg = "Failed to set the GPT-SoVITS model.";
+
+        error!(target: "stdout", "{}", err_msg);
+
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    backend::gpt_sovits::GPT_SOVITS_MODEL
+        .get()
+        .ok_or_else(|| ServerError::Operation("Failed to get the GPT-SoVITS model.".to_string()))
+}
+
+async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let path_str = req.uri().path();
     let path_buf = PathBuf::from(path_str);
     let mut path_iter = path_buf.iter();
@@ -150,86 +652,98 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
     let root_path = path_iter.next().unwrap_or_default();
     let root_path = "/".to_owned() + root_path.to_str().unwrap_or_default();
 
-    // check if the API key is valid
-    if let Some(auth_header) = req.headers().get("authorization") {
-        if !auth_header.is_empty() {
-            let auth_header = match auth_header.to_str() {
-                Ok(auth_header) => auth_header,
-                Err(e) => {
-                    let err_msg = format!("Failed to get authorization header: {}", e);
-                    return Ok(error::unauthorized(err_msg));
-                }
-            };
-
-            let api_key = auth_header.split(" ").nth(1).unwrap_or_default();
-            info!(target: "stdout", "API Key: {}", api_key);
+    // answer preflight requests for every route, before the API key is checked
+    if req.method() == Method::OPTIONS {
+        return Ok(cors::cors().preflight(&req));
+    }
 
-            if let Some(stored_api_key) = LLAMA_API_KEY.get() {
-                if api_key != stored_api_key {
-                    let err_msg = "Invalid API key.";
-                    return Ok(error::unauthorized(err_msg));
-                }
-            }
+    // check if the API key is valid
+    if let Some(auth) = auth::AUTH.get() {
+        if let Some(response) = auth.check(&mut req) {
+            return Ok(response);
         }
     }
 
-    // log request
-    {
-        let method = hyper::http::Method::as_str(req.method()).to_string();
-        let path = req.uri().path().to_string();
-        let version = format!("{:?}", req.version());
-        if req.method() == hyper::http::Method::POST {
-            let size: u64 = match req.headers().get("content-length") {
-                Some(content_length) => content_length.to_str().unwrap().parse().unwrap(),
-                None => 0,
-            };
-
-            info!(target: "stdout", "method: {}, http_version: {}, content-length: {}", method, version, size);
-            info!(target: "stdout", "endpoint: {}", path);
-        } else {
-            info!(target: "stdout", "method: {}, http_version: {}", method, version);
-            info!(target: "stdout", "endpoint: {}", path);
+    // the key name goes in the access log
+    let api_key = auth::api_key(&req);
+
+    // rate limit the API routes by API key, or by remote address
+    let rate_limiter = rate_limit::rate_limiter();
+    let client =
+        (root_path == "/v1" && rate_limiter.is_enabled()).then(|| rate_limit::Client::of(&req));
+    let rate_limited = match client.as_ref() {
+        Some(client) => {
+            req.extensions_mut().insert(client.clone());
+            rate_limiter.check_request(client).err()
         }
-    }
+        None => None,
+    };
 
-    let response = match root_path.as_str() {
-        "/echo" => Response::new(Body::from("echo test")),
-        "/v1" => backend::handle_llama_request(req).await,
-        _ => error::invalid_endpoint("The requested service endpoint is not found."),
+    let mut response = match rate_limited {
+        Some(e) => e.into_response(),
+        None => match root_path.as_str() {
+            "/echo" => Response::new(Body::from("echo test")),
+            "/health" => health::health_handler(req).await,
+            "/ready" => health::ready_handler(req).await,
+            "/version" => health::version_handler(req).await,
+            "/queue" => queue::queue_handler(req).await,
+            "/metrics" => metrics::metrics_handler(req).await,
+            "/shutdown" => shutdown::shutdown_handler(req).await,
+            "/v1" => backend::handle_llama_request(req).await,
+            _ => error::invalid_endpoint("The requested service endpoint is not found."),
+        },
     };
+    if let Some(client) = client.as_ref() {
+        rate_limiter.set_headers(client, response.headers_mut());
+    }
 
-    // log response
-    {
-        let status_code = response.status();
-        if status_code.as_u16() < 400 {
-            // log response
-            let response_version = format!("{:?}", response.version());
-            info!(target: "stdout", "response_version: {}", response_version);
-            let response_body_size: u64 = response.body().size_hint().lower();
-            info!(target: "stdout", "response_body_size: {}", response_body_size);
-            let response_status = status_code.as_u16();
-            info!(target: "stdout", "response_status: {}", response_status);
-            let response_is_success = status_code.is_success();
-            info!(target: "stdout", "response_is_success: {}", response_is_success);
-        } else {
-            let response_version = format!("{:?}", response.version());
-            error!(target: "stdout", "response_version: {}", response_version);
-            let response_body_size: u64 = response.body().size_hint().lower();
-            error!(target: "stdout", "response_body_size: {}", response_body_size);
-            let response_status = status_code.as_u16();
-            error!(target: "stdout", "response_status: {}", response_status);
-            let response_is_success = status_code.is_success();
-            error!(target: "stdout", "response_is_success: {}", response_is_success);
-            let response_is_client_error = status_code.is_client_error();
-            error!(target: "stdout", "response_is_client_error: {}", response_is_client_error);
-            let response_is_server_error = status_code.is_server_error();
-            error!(target: "stdout", "response_is_server_error: {}", response_is_server_error);
-        }
+    if let Some(api_key) = api_key {
+        response.extensions_mut().insert(api_key);
     }
 
     Ok(response)
 }
 
+/// The TTS engine served by the server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Backend {
+    /// Piper voices, run by the WasmEdge wasi-nn piper plugin.
+    #[cfg(feature = "piper")]
+    Piper,
+    /// GPT-SoVITS, run by the WasmEdge gpt_sovits plugin.
+    #[cfg(feature = "gpt_sovits")]
+    GptSovits,
+    /// Deterministic sine tones, for testing without any WasmEdge plugin.
+    Mock,
+}
+impl Backend {
+    /// Backends served when none is selected: every compiled-in engine, or the mock if there is none.
+    fn defaults() -> Vec<Backend> {
+        let engines: Vec<Backend> = Backend::value_variants()
+            .iter()
+            .copied()
+            .filter(|backend| *backend != Backend::Mock)
+            .collect();
+
+        match engines.is_empty() {
+            true => vec![Backend::Mock],
+            false => engines,
+        }
+    }
+}
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "piper")]
+            Backend::Piper => write!(f, "piper"),
+            #[cfg(feature = "gpt_sovits")]
+            Backend::GptSovits => write!(f, "gpt-sovits"),
+            Backend::Mock => write!(f, "mock"),
+        }
+    }
+}
+
 #[derive(
     Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize,
 )]
@@ -256,6 +770,29 @@ pub(crate) enum LogLevel {
     /// Describes messages indicating fatal errors.
     Critical,
 }
+/// Logger of native builds, such as the test server, where the `wasi:logging` interface used by
+/// `wasi_logger` does not exist. Messages are written to stdout, one per line.
+#[cfg(not(target_os = "wasi"))]
+struct StdoutLogger;
+#[cfg(not(target_os = "wasi"))]
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {
+        use std::io::Write;
+
+        let _ = std::io::stdout().flush();
+    }
+}
+
 impl From<LogLevel> for log::LevelFilter {
     fn from(level: LogLevel) -> Self {
         match level {
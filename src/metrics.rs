@@ -0,0 +1,429 @@
+//! Server metrics in the Prometheus text format, served at `/metrics`.
+//!
+//! Requests are counted by route and status, with a latency histogram per route. Each synthesis is
+//! recorded per voice: the characters synthesized, the seconds of audio produced, the seconds spent
+//! and the real-time factor, that is the time spent per second of audio. The load of the synthesis
+//! queue and the hits of the speech cache are read when the metrics are scraped.
+
+use crate::{
+    backend::{Synthesis, Voice},
+    error, queue,
+};
+use hyper::{http::Method, Body, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// routes reported by name, any other path is reported as `other`
+const ROUTES: [&str; 13] = [
+    "/echo",
+    "/health",
+    "/ready",
+    "/version",
+    "/queue",
+    "/metrics",
+    "/v1/audio/speech",
+    "/v1/audio/speech/ws",
+    "/v1/audio/speech_gpt",
+    "/v1/audio/voices",
+    "/v1/models",
+    "/v1/files",
+    "/v1/files/{id}",
+];
+
+// upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+// upper bounds of the real-time factor buckets
+const REAL_TIME_FACTOR_BUCKETS: [f64; 9] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0];
+
+// metrics of the server, created on first use
+static METRICS: OnceCell<Metrics> = OnceCell::new();
+
+pub(crate) fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// The route label of a request path.
+pub(crate) fn route(path: &str) -> &'static str {
+    if let Some(route) = ROUTES.iter().find(|route| **route == path) {
+        return route;
+    }
+
+    match path.starts_with("/v1/files/") {
+        true => "/v1/files/{id}",
+        false => "other",
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    requests: BTreeMap<(&'static str, u16), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    voices: BTreeMap<String, VoiceMetrics>,
+}
+
+#[derive(Debug)]
+struct VoiceMetrics {
+    chars: u64,
+    audio_seconds: f64,
+    synthesis_seconds: f64,
+    real_time_factor: Histogram,
+}
+
+impl Metrics {
+    /// Record a request answered with `status` after `elapsed`, the time until the response
+    /// headers, so a streamed response does not count the time its body is sent.
+    pub(crate) fn record_request(
+        &self,
+        route: &'static str,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+
+        *inner.requests.entry((route, status.as_u16())).or_default() += 1;
+        inner
+            .latency
+            .entry(route)
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record the synthesis of `chars` characters into `audio_seconds` of audio with `voice`.
+    pub(crate) fn record_synthesis(
+        &self,
+        voice: &str,
+        chars: usize,
+        audio_seconds: f64,
+        elapsed: Duration,
+    ) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+
+        let metrics = inner
+            .voices
+            .entry(voice.to_string())
+            .or_insert_with(|| VoiceMetrics {
+                chars: 0,
+                audio_seconds: 0.0,
+                synthesis_seconds: 0.0,
+                real_time_factor: Histogram::new(&REAL_TIME_FACTOR_BUCKETS),
+            });
+        metrics.chars += chars as u64;
+        metrics.audio_seconds += audio_seconds;
+        metrics.synthesis_seconds += elapsed.as_secs_f64();
+        if audio_seconds > 0.0 {
+            metrics
+                .real_time_factor
+                .observe(elapsed.as_secs_f64() / audio_seconds);
+        }
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        {
+            let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+
+            header(
+                &mut out,
+                "tts_http_requests_total",
+                "counter",
+                "HTTP requests by route and status.",
+            );
+            for ((route, status), count) in inner.requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "tts_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    route, status, count
+                );
+            }
+
+            header(
+                &mut out,
+                "tts_http_request_duration_seconds",
+                "histogram",
+                "Time until the response headers, by route.",
+            );
+            for (route, histogram) in inner.latency.iter() {
+                histogram.render(
+                    &mut out,
+                    "tts_http_request_duration_seconds",
+                    &format!("route=\"{}\"", route),
+                );
+            }
+
+            header(
+                &mut out,
+                "tts_synthesized_characters_total",
+                "counter",
+                "Characters synthesized, by voice.",
+            );
+            for (voice, metrics) in inner.voices.iter() {
+                let _ = writeln!(
+                    out,
+                    "tts_synthesized_characters_total{{voice=\"{}\"}} {}",
+                    escape(voice),
+                    metrics.chars
+                );
+            }
+
+            header(
+                &mut out,
+                "tts_audio_seconds_total",
+                "counter",
+                "Seconds of audio produced, by voice.",
+            );
+            for (voice, metrics) in inner.voices.iter() {
+                let _ = writeln!(
+                    out,
+                    "tts_audio_seconds_total{{voice=\"{}\"}} {}",
+                    escape(voice),
+                    metrics.audio_seconds
+                );
+            }
+
+            header(
+                &mut out,
+                "tts_synthesis_seconds_total",
+                "counter",
+                "Seconds spent synthesizing, by voice.",
+            );
+            for (voice, metrics) in inner.voices.iter() {
+                let _ = writeln!(
+                    out,
+                    "tts_synthesis_seconds_total{{voice=\"{}\"}} {}",
+                    escape(voice),
+                    metrics.synthesis_seconds
+                );
+            }
+
+            header(
+                &mut out,
+                "tts_real_time_factor",
+                "histogram",
+                "Seconds spent per second of audio, by voice.",
+            );
+            for (voice, metrics) in inner.voices.iter() {
+                metrics.real_time_factor.render(
+                    &mut out,
+                    "tts_real_time_factor",
+                    &format!("voice=\"{}\"", escape(voice)),
+                );
+            }
+        }
+
+        let queue = queue::synthesis_queue();
+        header(
+            &mut out,
+            "tts_queue_in_flight",
+            "gauge",
+            "Syntheses running.",
+        );
+        let _ = writeln!(out, "tts_queue_in_flight {}", queue.in_flight());
+        header(
+            &mut out,
+            "tts_queue_depth",
+            "gauge",
+            "Requests waiting for a synthesis slot.",
+        );
+        let _ = writeln!(out, "tts_queue_depth {}", queue.depth());
+
+        #[cfg(feature = "piper")]
+        if let Some(cache) = crate::cache::SPEECH_CACHE.get() {
+            let (hits, misses) = cache.hits_and_misses();
+            header(
+                &mut out,
+                "tts_cache_hits_total",
+                "counter",
+                "Sentences served from the speech cache.",
+            );
+            let _ = writeln!(out, "tts_cache_hits_total {}", hits);
+            header(
+                &mut out,
+                "tts_cache_misses_total",
+                "counter",
+                "Sentences not found in the speech cache.",
+            );
+            let _ = writeln!(out, "tts_cache_misses_total {}", misses);
+            header(
+                &mut out,
+                "tts_cache_hit_ratio",
+                "gauge",
+                "Share of the sentences served from the speech cache.",
+            );
+            let ratio = match hits + misses {
+                0 => 0.0,
+                lookups => hits as f64 / lookups as f64,
+            };
+            let _ = writeln!(out, "tts_cache_hit_ratio {}", ratio);
+        }
+
+        out
+    }
+}
+
+/// A voice that records each synthesis in the metrics, labeled with the resolved voice. Audio served
+/// from the speech cache was not synthesized, so it is left out.
+pub(crate) struct MeasuredVoice {
+    pub(crate) inner: Box<dyn Voice>,
+}
+impl Voice for MeasuredVoice {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn alias(&self) -> Option<&str> {
+        self.inner.alias()
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Synthesis, String> {
+        let start = Instant::now();
+        let synthesis = self.inner.synthesize(text)?;
+        if !synthesis.cached {
+            metrics().record_synthesis(
+                self.inner.name(),
+                text.chars().count(),
+                synthesis.pcm.duration(),
+                start.elapsed(),
+            );
+        }
+
+        Ok(synthesis)
+    }
+}
+
+/// Cumulative buckets, as Prometheus expects them.
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics to Prometheus.
+///
+/// - `GET /metrics`
+pub(crate) async fn metrics_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    let result = Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .body(Body::from(metrics().render()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Pcm;
+
+    // a voice serving `cached` from the speech cache, and synthesizing anything else
+    struct CachingVoice;
+    impl Voice for CachingVoice {
+        fn name(&self) -> &str {
+            "caching"
+        }
+
+        fn synthesize(&self, text: &str) -> Result<Synthesis, String> {
+            let mut synthesis = Synthesis::from(Pcm {
+                samples: vec![0; 22050],
+                sample_rate: 22050,
+                channels: 1,
+            });
+            synthesis.cached = text == "cached";
+
+            Ok(synthesis)
+        }
+    }
+
+    #[test]
+    fn cache_hits_are_not_recorded_as_syntheses() {
+        let voice = MeasuredVoice {
+            inner: Box::new(CachingVoice),
+        };
+        voice.synthesize("synthesized").unwrap();
+        voice.synthesize("cached").unwrap();
+
+        let rendered = metrics().render();
+        assert!(rendered.contains("tts_synthesized_characters_total{voice=\"caching\"} 11\n"));
+        assert!(rendered.contains("tts_audio_seconds_total{voice=\"caching\"} 1\n"));
+        assert!(rendered.contains("tts_real_time_factor_count{voice=\"caching\"} 1\n"));
+    }
+}
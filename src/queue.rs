@@ -0,0 +1,169 @@
+//! Bounds the number of syntheses running at once, and of the requests waiting for their turn.
+//!
+//! A request that finds every synthesis slot taken waits in the queue, up to a timeout. When the
+//! queue is full or the wait times out, it is rejected with `503 Service Unavailable` and a
+//! `Retry-After` header, so that clients back off instead of piling up.
+
+use crate::error::{self, ServerError};
+use hyper::{http::Method, Body, Request, Response};
+use once_cell::sync::OnceCell;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default maximum number of syntheses running at once.
+pub(crate) const DEFAULT_MAX_CONCURRENT: usize = 4;
+/// Default maximum number of requests waiting for a synthesis slot.
+pub(crate) const DEFAULT_MAX_QUEUED: usize = 16;
+/// Default time a request may wait for a synthesis slot, in seconds.
+pub(crate) const DEFAULT_QUEUE_TIMEOUT_SECS: f64 = 30.0;
+
+// the synthesis queue, set at startup
+pub(crate) static SYNTHESIS_QUEUE: OnceCell<SynthesisQueue> = OnceCell::new();
+
+/// The synthesis queue in use: the one set at startup, or one with the default bounds.
+pub(crate) fn synthesis_queue() -> &'static SynthesisQueue {
+    SYNTHESIS_QUEUE.get_or_init(|| {
+        SynthesisQueue::new(
+            DEFAULT_MAX_CONCURRENT,
+            DEFAULT_MAX_QUEUED,
+            Duration::from_secs_f64(DEFAULT_QUEUE_TIMEOUT_SECS),
+        )
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct SynthesisQueue {
+    // `None` when the number of syntheses is not limited
+    slots: Option<Arc<Semaphore>>,
+    max_concurrent: usize,
+    max_queued: usize,
+    timeout: Duration,
+    queued: AtomicUsize,
+}
+impl SynthesisQueue {
+    /// `max_concurrent` of `0` lifts the limit on concurrent syntheses, and with it the queue.
+    pub(crate) fn new(max_concurrent: usize, max_queued: usize, timeout: Duration) -> Self {
+        SynthesisQueue {
+            slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_concurrent,
+            max_queued,
+            timeout,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a synthesis slot. The slot is given back when the returned permit is dropped.
+    pub(crate) async fn acquire(&self) -> Result<SynthesisPermit, ServerError> {
+        let Some(slots) = self.slots.as_ref() else {
+            return Ok(SynthesisPermit { _slot: None });
+        };
+
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(SynthesisPermit {
+                _slot: Some(permit),
+            });
+        }
+
+        // join the queue unless it is full
+        let joined = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.max_queued).then_some(queued + 1)
+            });
+        if joined.is_err() {
+            return Err(self.unavailable(format!(
+                "The server is busy: {} syntheses are running and {} requests are waiting.",
+                self.max_concurrent, self.max_queued
+            )));
+        }
+        let _queued = Queued(&self.queued);
+
+        match tokio::time::timeout(self.timeout, slots.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(SynthesisPermit {
+                _slot: Some(permit),
+            }),
+            Ok(Err(e)) => Err(ServerError::Operation(format!(
+                "Failed to wait for a synthesis slot. {}",
+                e
+            ))),
+            Err(_) => Err(self.unavailable(format!(
+                "The server is busy: no synthesis slot was free within {} seconds.",
+                self.timeout.as_secs_f64()
+            ))),
+        }
+    }
+
+    /// Number of syntheses running.
+    pub(crate) fn in_flight(&self) -> usize {
+        match self.slots.as_ref() {
+            Some(slots) => self.max_concurrent - slots.available_permits(),
+            None => 0,
+        }
+    }
+
+    /// Number of requests waiting for a synthesis slot.
+    pub(crate) fn depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    fn unavailable(&self, message: String) -> ServerError {
+        ServerError::Unavailable {
+            message,
+            // a slot is likely free again by the time a waiting request would have given up
+            retry_after: Some(self.timeout.as_secs_f64().ceil().max(1.0) as u64),
+        }
+    }
+}
+
+/// A synthesis slot, held until dropped.
+pub(crate) struct SynthesisPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+// leaves the queue when the wait ends, including when the request is dropped while waiting
+struct Queued<'a>(&'a AtomicUsize);
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Report the load of the synthesis queue, for load balancers.
+///
+/// - `GET /queue`
+pub(crate) async fn queue_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method(), "GET");
+    }
+
+    let queue = synthesis_queue();
+    let status = serde_json::json!({
+        "in_flight": queue.in_flight(),
+        "max_concurrent": queue.max_concurrent,
+        "queued": queue.depth(),
+        "max_queued": queue.max_queued,
+    });
+
+    let result = Response::builder()
+        .header("Content-Type", "application/json")
+        .header("X-Queue-Depth", queue.depth())
+        .body(Body::from(status.to_string()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Per-client rate limiting of the API routes with token buckets.
+//!
+//! A client is the API key of the request, or its remote address when API keys are not used. Each
+//! client has a bucket of requests and a bucket of synthesized characters, refilled continuously up
+//! to the per-minute limit. The state of the buckets is sent back in `X-RateLimit-*` headers, and a
+//! request over a limit is rejected with `429 Too Many Requests` and a `Retry-After` header. An input
+//! longer than the character limit never fits, so it is rejected with `400 Bad Request`.
+
+use crate::{auth, error::ServerError};
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Body, Request,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+    time::Instant,
+};
+
+// once this many clients are tracked, the least recently seen one is forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+// rate limits, set at startup
+pub(crate) static RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::new();
+
+/// The rate limiter in use: the one set at startup, or one without limits.
+pub(crate) fn rate_limiter() -> &'static RateLimiter {
+    RATE_LIMITER.get_or_init(|| RateLimiter::new(0, 0))
+}
+
+/// Who a request is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Client {
+    ApiKey(String),
+    Addr(IpAddr),
+}
+impl Client {
+    /// The API key that authenticated the request, or else its remote address.
+    pub(crate) fn of(req: &Request<Body>) -> Self {
+        match auth::api_key(req) {
+            Some(api_key) => Client::ApiKey(api_key.name.clone()),
+            None => Client::Addr(
+                req.extensions()
+                    .get::<SocketAddr>()
+                    .map(|addr| addr.ip())
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ),
+        }
+    }
+}
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::ApiKey(name) => write!(f, "API key `{}`", name),
+            Client::Addr(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// The client a request is counted against, if the request is rate limited.
+pub(crate) fn client(req: &Request<Body>) -> Option<&Client> {
+    req.extensions().get::<Client>()
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    requests_per_minute: u64,
+    chars_per_minute: u64,
+    clients: Mutex<Clients>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    buckets: HashMap<Client, Buckets>,
+    // the clients by the tick of their last request, least recently seen first
+    order: BTreeMap<u64, Client>,
+    // incremented on each request
+    tick: u64,
+}
+impl Clients {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl RateLimiter {
+    /// `0` disables a limit.
+    pub(crate) fn new(requests_per_minute: u64, chars_per_minute: u64) -> Self {
+        RateLimiter {
+            requests_per_minute,
+            chars_per_minute,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.requests_per_minute > 0 || self.chars_per_minute > 0
+    }
+
+    /// Count a request of `client`.
+    pub(crate) fn check_request(&self, client: &Client) -> Result<(), ServerError> {
+        if self.requests_per_minute == 0 {
+            return Ok(());
+        }
+
+        self.with_buckets(client, |buckets, now| {
+            buckets.requests.take(1.0, self.requests_per_minute, now)
+        })
+        .map_err(|retry_after| ServerError::TooManyRequests {
+            message: format!(
+                "Rate limit reached for {}: {} requests per minute.",
+                client, self.requests_per_minute
+            ),
+            retry_after,
+        })
+    }
+
+    /// Count `chars` characters to synthesize for `client`.
+    pub(crate) fn check_chars(&self, client: &Client, chars: u64) -> Result<(), ServerError> {
+        if self.chars_per_minute == 0 {
+            return Ok(());
+        }
+
+        // the bucket never holds more than a minute of characters
+        if chars > self.chars_per_minute {
+            return Err(ServerError::TooLong {
+                param: "input",
+                message: format!(
+                    "The input has {} characters, more than the rate limit of {} characters per minute.",
+                    chars, self.chars_per_minute
+                ),
+            });
+        }
+
+        self.with_buckets(client, |buckets, now| {
+            buckets.chars.take(chars as f64, self.chars_per_minute, now)
+        })
+        .map_err(|retry_after| ServerError::TooManyRequests {
+            message: format!(
+                "Rate limit reached for {}: {} characters per minute.",
+                client, self.chars_per_minute
+            ),
+            retry_after,
+        })
+    }
+
+    /// Give back `chars` characters taken by [`RateLimiter::check_chars`], for a request that failed.
+    pub(crate) fn refund_chars(&self, client: &Client, chars: u64) {
+        if self.chars_per_minute == 0 {
+            return;
+        }
+
+        self.with_buckets(client, |buckets, now| {
+            buckets.chars.put(chars as f64, self.chars_per_minute, now)
+        })
+    }
+
+    /// Set the `X-RateLimit-*` headers of a response to the state of the buckets of `client`.
+    ///
+    /// `Reset` headers give the seconds until a bucket is full again.
+    pub(crate) fn set_headers(&self, client: &Client, headers: &mut HeaderMap) {
+        let limits = [
+            ("requests", self.requests_per_minute),
+            ("characters", self.chars_per_minute),
+        ];
+        let states = self.with_buckets(client, |buckets, now| {
+            [
+                buckets.requests.state(self.requests_per_minute, now),
+                buckets.chars.state(self.chars_per_minute, now),
+            ]
+        });
+
+        for ((name, limit), (remaining, reset)) in limits.into_iter().zip(states) {
+            if limit == 0 {
+                continue;
+            }
+            for (header, value) in [("limit", limit), ("remaining", remaining), ("reset", reset)] {
+                if let Ok(header) = format!("x-ratelimit-{}-{}", header, name).parse::<HeaderName>()
+                {
+                    headers.insert(header, HeaderValue::from(value));
+                }
+            }
+        }
+    }
+
+    fn with_buckets<T>(&self, client: &Client, f: impl FnOnce(&mut Buckets, Instant) -> T) -> T {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let clients = &mut *clients;
+
+        let tick = clients.next_tick();
+        match clients.buckets.get_mut(client) {
+            Some(buckets) => {
+                let last_seen = std::mem::replace(&mut buckets.seen, tick);
+                clients.order.remove(&last_seen);
+            }
+            None => {
+                // the client seen least recently makes room, and starts with full buckets if it returns
+                while clients.buckets.len() >= MAX_CLIENTS {
+                    let Some((_, least_recent)) = clients.order.pop_first() else {
+                        break;
+                    };
+                    clients.buckets.remove(&least_recent);
+                }
+            }
+        }
+        clients.order.insert(tick, client.clone());
+
+        let buckets = clients
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| Buckets {
+                requests: Bucket::full(self.requests_per_minute, now),
+                chars: Bucket::full(self.chars_per_minute, now),
+                seen: tick,
+            });
+
+        f(buckets, now)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Bucket,
+    chars: Bucket,
+    // the tick of the last request of the client
+    seen: u64,
+}
+
+/// A token bucket holding up to `per_minute` tokens, refilled at `per_minute` tokens per minute.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    fn full(per_minute: u64, now: Instant) -> Self {
+        Bucket {
+            tokens: per_minute as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, per_minute: u64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.updated = now;
+    }
+
+    /// Take `tokens`, or return the seconds to wait until there are enough.
+    fn take(&mut self, tokens: f64, per_minute: u64, now: Instant) -> Result<(), u64> {
+        self.refill(per_minute, now);
+
+        if self.tokens >= tokens {
+            self.tokens -= tokens;
+            Ok(())
+        } else {
+            Err(seconds_to_refill(tokens - self.tokens, per_minute))
+        }
+    }
+
+    /// Put back `tokens` taken before.
+    fn put(&mut self, tokens: f64, per_minute: u64, now: Instant) {
+        self.refill(per_minute, now);
+
+        self.tokens = (self.tokens + tokens).min(per_minute as f64);
+    }
+
+    /// The tokens left, and the seconds until the bucket is full.
+    fn state(&mut self, per_minute: u64, now: Instant) -> (u64, u64) {
+        self.refill(per_minute, now);
+
+        (
+            self.tokens as u64,
+            seconds_to_refill(per_minute as f64 - self.tokens, per_minute),
+        )
+    }
+}
+
+fn seconds_to_refill(missing: f64, per_minute: u64) -> u64 {
+    if per_minute == 0 || missing <= 0.0 {
+        return 0;
+    }
+
+    (missing * 60.0 / per_minute as f64).ceil() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: usize) -> Client {
+        Client::Addr(IpAddr::V4(Ipv4Addr::from(n as u32)))
+    }
+
+    #[test]
+    fn the_least_recently_seen_client_makes_room() {
+        let rate_limiter = RateLimiter::new(10, 0);
+        for n in 0..MAX_CLIENTS {
+            rate_limiter.check_request(&addr(n)).unwrap();
+        }
+        // the first client is seen again, so the second one is the least recent
+        rate_limiter.check_request(&addr(0)).unwrap();
+
+        rate_limiter.check_request(&addr(MAX_CLIENTS)).unwrap();
+
+        let clients = rate_limiter.clients.lock().unwrap();
+        assert_eq!(clients.buckets.len(), MAX_CLIENTS);
+        assert_eq!(clients.order.len(), MAX_CLIENTS);
+        assert!(clients.buckets.contains_key(&addr(0)));
+        assert!(!clients.buckets.contains_key(&addr(1)));
+    }
+
+    #[test]
+    fn inputs_over_the_character_limit_never_fit() {
+        let rate_limiter = RateLimiter::new(0, 20);
+
+        let e = rate_limiter.check_chars(&addr(0), 21).unwrap_err();
+        assert!(matches!(e, ServerError::TooLong { param: "input", .. }));
+
+        rate_limiter.check_chars(&addr(0), 20).unwrap();
+        let e = rate_limiter.check_chars(&addr(0), 1).unwrap_err();
+        assert!(matches!(
+            e,
+            ServerError::TooManyRequests { retry_after: 3, .. }
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Graceful shutdown on `SIGTERM` or `SIGINT`, or on a `POST /shutdown` request.
+//!
+//! On a signal the server stops accepting connections, `/ready` reports `503 Service Unavailable`,
+//! and the requests in flight, streamed responses and WebSocket sessions included, may run until
+//! the shutdown timeout. Those still running then are logged and aborted. WASI has no signals, so
+//! a server run by WasmEdge drains only when asked by the `/shutdown` route, if it is enabled.
+
+use crate::error;
+use hyper::{http::Method, Body, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// Default seconds the requests in flight may run after a shutdown signal.
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT_SECS: f64 = 30.0;
+
+// whether `POST /shutdown` is served, set at startup
+pub(crate) static SHUTDOWN_ROUTE: OnceCell<bool> = OnceCell::new();
+
+// state of the shutdown, created on first use
+static SHUTDOWN: OnceCell<Shutdown> = OnceCell::new();
+
+pub(crate) fn shutdown() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(Shutdown::default)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    // when the shutdown signal was received
+    draining_since: OnceCell<Instant>,
+    draining: Notify,
+    // a `POST /shutdown` request
+    requested: Notify,
+    // descriptions of the requests in flight, by id
+    in_flight: Mutex<BTreeMap<u64, String>>,
+    next_id: AtomicU64,
+    idle: Notify,
+}
+impl Shutdown {
+    /// Whether a shutdown signal was received.
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining_since.get().is_some()
+    }
+
+    /// Wait for a shutdown signal or request, then start draining.
+    pub(crate) async fn signal(&self) {
+        let signal = tokio::select! {
+            signal = wait_for_signal() => signal,
+            _ = self.requested.notified() => "a shutdown request",
+        };
+
+        if self.draining_since.set(Instant::now()).is_ok() {
+            // log
+            info!(target: "stdout", "Received {}, shutting down with {} requests in flight", signal, self.in_flight().len());
+
+            self.draining.notify_waiters();
+        }
+    }
+
+    /// Start draining, as on a shutdown signal.
+    pub(crate) fn request(&self) {
+        // the permit is kept until the server waits for it
+        self.requested.notify_one();
+    }
+
+    /// Track a request until the returned guard is dropped.
+    pub(crate) fn track(&self, description: String) -> InFlight {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, description);
+
+        InFlight { id }
+    }
+
+    /// Descriptions of the requests in flight.
+    pub(crate) fn in_flight(&self) -> Vec<String> {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // wait until `timeout` after the shutdown signal
+    async fn deadline(&self, timeout: Duration) {
+        let draining = self.draining.notified();
+        let since = match self.draining_since.get() {
+            Some(since) => *since,
+            None => {
+                draining.await;
+                self.draining_since
+                    .get()
+                    .copied()
+                    .unwrap_or_else(Instant::now)
+            }
+        };
+
+        tokio::time::sleep_until((since + timeout).into()).await;
+    }
+
+    // wait until no request is in flight
+    async fn idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight().is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Start a graceful shutdown, if the route is enabled with `shutdown_route` in the `[server]`
+/// section of the configuration file.
+///
+/// - `POST /shutdown`
+pub(crate) async fn shutdown_handler(req: Request<Body>) -> Response<Body> {
+    if !SHUTDOWN_ROUTE.get().copied().unwrap_or_default() {
+        return error::invalid_endpoint(req.uri().path());
+    }
+    if req.method() != Method::POST {
+        return error::method_not_allowed(req.method(), "POST");
+    }
+
+    let shutdown = shutdown();
+    // the request itself is in flight too
+    let in_flight = shutdown.in_flight().len().saturating_sub(1);
+    shutdown.request();
+
+    let result = Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::json!({ "status": "draining", "in_flight": in_flight }).to_string(),
+        ));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// A request in flight, tracked until it is dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    id: u64,
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let shutdown = shutdown();
+        let mut in_flight = shutdown.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(&self.id);
+        if in_flight.is_empty() {
+            shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+/// Run `server`, a server shut down gracefully on [`Shutdown::signal`], then wait for the requests
+/// still in flight, at most `timeout` after the signal.
+///
+/// Upgraded WebSocket connections outlive the server, so they are waited for once it returns.
+pub(crate) async fn drain(
+    server: impl Future<Output = Result<(), hyper::Error>>,
+    timeout: Duration,
+) -> Result<(), hyper::Error> {
+    let shutdown = shutdown();
+    let deadline = shutdown.deadline(timeout);
+    tokio::pin!(deadline);
+
+    tokio::select! {
+        result = server => result?,
+        _ = &mut deadline => {
+            abort(shutdown);
+            return Ok(());
+        }
+    }
+
+    tokio::select! {
+        _ = shutdown.idle() => {
+            // log
+            info!(target: "stdout", "All requests drained, the server is shut down");
+        }
+        _ = &mut deadline => abort(shutdown),
+    }
+
+    Ok(())
+}
+
+// the requests still in flight are aborted when the runtime is dropped
+fn abort(shutdown: &Shutdown) {
+    for description in shutdown.in_flight() {
+        // log
+        error!(target: "stdout", "Aborting {} after the shutdown timeout", description);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            // log
+            error!(target: "stdout", "Failed to listen for SIGTERM. {}", e);
+
+            std::future::pending().await
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    std::future::pending().await
+}
@@ -0,0 +1,211 @@
+//! Text segmentation used to synthesize long inputs sentence by sentence.
+
+// abbreviations whose trailing period does not end a sentence
+const ABBREVIATIONS: [&str; 12] = [
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "etc", "e.g", "i.e",
+];
+
+/// Split `text` into sentences, keeping the terminating punctuation with each sentence.
+///
+/// Empty and whitespace-only segments are dropped.
+pub(crate) fn split_sentences(text: impl AsRef<str>) -> Vec<String> {
+    let text = text.as_ref();
+
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for end in boundaries(text, true) {
+        push_sentence(&mut sentences, &text[start..end]);
+        start = end;
+    }
+    push_sentence(&mut sentences, &text[start..]);
+
+    sentences
+}
+
+/// Accumulates text that arrives in fragments, e.g. LLM tokens, and hands out complete sentences.
+#[derive(Debug, Default)]
+pub(crate) struct SentenceBuffer {
+    buf: String,
+}
+impl SentenceBuffer {
+    /// Append a fragment and return the sentences it completed.
+    ///
+    /// A terminator at the very end of the buffer is not trusted until the next fragment arrives,
+    /// since "3." may turn out to be "3.14".
+    pub(crate) fn push(&mut self, fragment: impl AsRef<str>) -> Vec<String> {
+        self.buf.push_str(fragment.as_ref());
+
+        let end = match boundaries(&self.buf, false).last() {
+            Some(&end) => end,
+            None => return Vec::new(),
+        };
+        let rest = self.buf.split_off(end);
+        let complete = std::mem::replace(&mut self.buf, rest);
+
+        split_sentences(complete)
+    }
+
+    /// Return whatever is buffered as sentences, complete or not.
+    pub(crate) fn flush(&mut self) -> Vec<String> {
+        split_sentences(std::mem::take(&mut self.buf))
+    }
+
+    /// Number of characters waiting for the end of their sentence.
+    pub(crate) fn pending_chars(&self) -> usize {
+        self.buf.chars().count()
+    }
+}
+
+/// Byte offsets just past the end of each sentence in `text`.
+///
+/// If `eager` is false, a `.`, `!` or `?` at the very end of `text` is not treated as a boundary.
+fn boundaries(text: &str, eager: bool) -> Vec<usize> {
+    let mut boundaries = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        let end = idx + c.len_utf8();
+        // the sentence breaks at whitespace, past any closing quotes and brackets
+        let at_break = match text[end..].chars().find(|next| !is_closing(*next)) {
+            Some(next) => next.is_whitespace(),
+            None => eager,
+        };
+        let boundary = match c {
+            // full-width terminators need no trailing whitespace
+            '。' | '！' | '？' | '\n' => true,
+            '!' | '?' | ';' => at_break,
+            '.' => at_break && !is_abbreviation(&text[start..idx]),
+            _ => false,
+        };
+
+        if boundary {
+            // swallow closing quotes and brackets that belong to the sentence
+            let mut end = end;
+            while let Some(&(idx, next)) = chars.peek() {
+                if is_closing(next) {
+                    end = idx + next.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            boundaries.push(end);
+            start = end;
+        }
+    }
+
+    boundaries
+}
+
+fn push_sentence(sentences: &mut Vec<String>, sentence: &str) {
+    let sentence = sentence.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence.to_string());
+    }
+}
+
+fn is_closing(c: char) -> bool {
+    matches!(c, '"' | '\'' | ')' | ']' | '”' | '’' | '」' | '』')
+}
+
+fn is_abbreviation(prefix: &str) -> bool {
+    let word = prefix
+        .rsplit(|c: char| c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    // single letters are initials, e.g. "J. R. R. Tolkien"
+    ABBREVIATIONS.contains(&word.as_str())
+        || (word.chars().count() == 1 && word.chars().all(char::is_alphabetic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentences_keep_their_terminators() {
+        assert_eq!(
+            split_sentences("Hello there! How are you? I am fine; thanks. Bye"),
+            [
+                "Hello there!",
+                "How are you?",
+                "I am fine;",
+                "thanks.",
+                "Bye"
+            ]
+        );
+    }
+
+    #[test]
+    fn abbreviations_and_initials_do_not_end_sentences() {
+        assert_eq!(
+            split_sentences("Dr. Smith met J. R. R. Tolkien, e.g. at St. Mary's. Then he left."),
+            [
+                "Dr. Smith met J. R. R. Tolkien, e.g. at St. Mary's.",
+                "Then he left."
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_do_not_end_sentences() {
+        assert_eq!(
+            split_sentences("Pi is 3.14 or so. Yes."),
+            ["Pi is 3.14 or so.", "Yes."]
+        );
+    }
+
+    #[test]
+    fn closing_quotes_stay_with_their_sentence() {
+        assert_eq!(
+            split_sentences("He said \"stop.\" (Really!) Fine."),
+            ["He said \"stop.\"", "(Really!)", "Fine."]
+        );
+    }
+
+    #[test]
+    fn full_width_terminators_and_newlines_end_sentences() {
+        assert_eq!(
+            split_sentences("你好。今天好吗？\nNew line\n\nAgain"),
+            ["你好。", "今天好吗？", "New line", "Again"]
+        );
+    }
+
+    #[test]
+    fn buffer_hands_out_complete_sentences() {
+        let mut buffer = SentenceBuffer::default();
+
+        assert!(buffer.push("Hello, wor").is_empty());
+        assert_eq!(buffer.push("ld! How are"), ["Hello, world!"]);
+        assert_eq!(buffer.pending_chars(), " How are".chars().count());
+        assert_eq!(buffer.push(" you? Fine. And"), ["How are you?", "Fine."]);
+        assert_eq!(buffer.flush(), ["And"]);
+        assert_eq!(buffer.pending_chars(), 0);
+        assert!(buffer.flush().is_empty());
+    }
+
+    #[test]
+    fn buffer_waits_for_the_text_after_a_terminator() {
+        let mut buffer = SentenceBuffer::default();
+
+        // "3." may continue as "3.14"
+        assert!(buffer.push("Pi is 3.").is_empty());
+        assert!(buffer.push("14.").is_empty());
+        assert_eq!(buffer.push(" Yes"), ["Pi is 3.14."]);
+
+        // a closing quote may follow
+        assert_eq!(buffer.push(". He said \"stop."), ["Yes."]);
+        assert!(buffer.push("\"").is_empty());
+        assert_eq!(buffer.push(" Then"), ["He said \"stop.\""]);
+        assert_eq!(buffer.flush(), ["Then"]);
+    }
+
+    #[test]
+    fn blank_input_has_no_sentences() {
+        assert!(split_sentences("").is_empty());
+        assert!(split_sentences("  \n\t ").is_empty());
+    }
+}
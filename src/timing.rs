@@ -0,0 +1,202 @@
+//! Time spent in each stage of a speech request, for the access log, the `Server-Timing` header and
+//! the closing event of server-sent speech events.
+//!
+//! The stages are the wait for a synthesis slot, the splitting of the input into sentences, the
+//! lookups served by the speech cache, the synthesis by the backend and the encoding of the audio.
+//! Piper phonemizes with espeak-ng and runs the ONNX model in a single call of the wasi-nn plugin, so
+//! both are timed together as synthesis.
+
+use hyper::{header::HeaderValue, HeaderMap};
+use once_cell::sync::OnceCell;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// whether responses carry the timings in headers, set at startup
+pub(crate) static TIMING_HEADERS: OnceCell<bool> = OnceCell::new();
+
+/// Whether responses report the timings, in headers or in the closing event of a stream.
+pub(crate) fn enabled() -> bool {
+    TIMING_HEADERS.get().copied().unwrap_or_default()
+}
+
+/// A stage of a speech request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Waiting for a slot of the synthesis queue.
+    Queue,
+    /// Splitting the input into sentences, for streamed responses.
+    Text,
+    /// Audio served from the speech cache instead of synthesized.
+    Cache,
+    /// Phonemization and inference in the backend.
+    Synthesis,
+    /// Encoding the audio in the response format.
+    Encode,
+}
+impl Stage {
+    const ALL: [Stage; 5] = [
+        Stage::Queue,
+        Stage::Text,
+        Stage::Cache,
+        Stage::Synthesis,
+        Stage::Encode,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Queue => "queue",
+            Stage::Text => "text",
+            Stage::Cache => "cache",
+            Stage::Synthesis => "synthesis",
+            Stage::Encode => "encode",
+        }
+    }
+}
+
+/// Durations of the stages of a request, and the seconds of audio it produced.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Timings {
+    durations: [Option<Duration>; 5],
+    audio_seconds: f64,
+    // the part of the audio that was synthesized rather than served from the speech cache
+    synthesized_seconds: f64,
+}
+impl Timings {
+    /// Seconds spent synthesizing per second of synthesized audio.
+    pub(crate) fn real_time_factor(&self) -> Option<f64> {
+        let synthesis = self.durations[Stage::Synthesis as usize]?;
+
+        (self.synthesized_seconds > 0.0).then(|| synthesis.as_secs_f64() / self.synthesized_seconds)
+    }
+
+    // the stages that ran, with their durations in milliseconds
+    fn stages_ms(&self) -> impl Iterator<Item = (&'static str, f64)> + '_ {
+        Stage::ALL.into_iter().filter_map(|stage| {
+            self.durations[stage as usize].map(|d| (stage.name(), round(d.as_secs_f64() * 1e3)))
+        })
+    }
+
+    /// `key=value` pairs for the text access log.
+    pub(crate) fn to_text(&self) -> String {
+        let mut text: Vec<String> = self
+            .stages_ms()
+            .map(|(stage, ms)| format!("{}_ms={}", stage, ms))
+            .collect();
+        if self.audio_seconds > 0.0 {
+            text.push(format!("audio_seconds={}", round(self.audio_seconds)));
+        }
+        if let Some(rtf) = self.real_time_factor() {
+            text.push(format!("rtf={}", round(rtf)));
+        }
+
+        text.join(" ")
+    }
+
+    /// A JSON object for the JSON access log.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        for (stage, ms) in self.stages_ms() {
+            json.insert(format!("{}_ms", stage), ms.into());
+        }
+        if self.audio_seconds > 0.0 {
+            json.insert(
+                "audio_seconds".to_string(),
+                round(self.audio_seconds).into(),
+            );
+        }
+        if let Some(rtf) = self.real_time_factor() {
+            json.insert("rtf".to_string(), round(rtf).into());
+        }
+
+        json.into()
+    }
+
+    /// Set the `Server-Timing`, `X-TTS-Audio-Seconds` and `X-TTS-Real-Time-Factor` headers, if
+    /// enabled.
+    pub(crate) fn set_headers(&self, headers: &mut HeaderMap) {
+        if !enabled() {
+            return;
+        }
+
+        let server_timing: Vec<String> = self
+            .stages_ms()
+            .map(|(stage, ms)| format!("{};dur={}", stage, ms))
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&server_timing.join(", ")) {
+            headers.insert("server-timing", value);
+        }
+        if self.audio_seconds > 0.0 {
+            if let Ok(value) = HeaderValue::from_str(&round(self.audio_seconds).to_string()) {
+                headers.insert("x-tts-audio-seconds", value);
+            }
+        }
+        if let Some(rtf) = self.real_time_factor() {
+            if let Ok(value) = HeaderValue::from_str(&round(rtf).to_string()) {
+                headers.insert("x-tts-real-time-factor", value);
+            }
+        }
+    }
+}
+
+/// Timings shared between a request and the task streaming its response.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedTimings(Arc<Mutex<Timings>>);
+impl SharedTimings {
+    /// Add `duration` to the time spent in `stage`.
+    pub(crate) fn add(&self, stage: Stage, duration: Duration) {
+        let mut timings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let total = timings.durations[stage as usize].get_or_insert(Duration::ZERO);
+        *total += duration;
+    }
+
+    /// Add `duration` to the synthesis, or to the cache lookups for audio served from the speech
+    /// cache.
+    pub(crate) fn add_synthesis(&self, duration: Duration, cached: bool) {
+        let stage = match cached {
+            true => Stage::Cache,
+            false => Stage::Synthesis,
+        };
+        self.add(stage, duration);
+    }
+
+    /// Add `seconds` of audio, which only count toward the real-time factor if they were synthesized.
+    pub(crate) fn add_audio(&self, seconds: f64, cached: bool) {
+        let mut timings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        timings.audio_seconds += seconds;
+        if !cached {
+            timings.synthesized_seconds += seconds;
+        }
+    }
+
+    pub(crate) fn get(&self) -> Timings {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+// three decimals are plenty for milliseconds and ratios
+fn round(value: f64) -> f64 {
+    (value * 1e3).round() / 1e3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_audio_is_left_out_of_the_real_time_factor() {
+        let timings = SharedTimings::default();
+        timings.add_synthesis(Duration::from_millis(500), false);
+        timings.add_audio(2.0, false);
+        timings.add_synthesis(Duration::from_millis(1), true);
+        timings.add_audio(3.0, true);
+
+        let timings = timings.get();
+        assert_eq!(timings.real_time_factor(), Some(0.25));
+        assert_eq!(
+            timings.to_text(),
+            "cache_ms=1 synthesis_ms=500 audio_seconds=5 rtf=0.25"
+        );
+    }
+}
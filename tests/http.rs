@@ -0,0 +1,2147 @@
+//! End-to-end tests of the HTTP routes, run against the server binary with the mock backend.
+//!
+//! The server is built for WASI by default, so the tests run natively:
+//!
+//! ```bash
+//! cargo test --target x86_64-unknown-linux-gnu --no-default-features
+//! ```
+
+#![cfg(not(target_os = "wasi"))]
+
+use hyper::{body::Bytes, header::HeaderMap, Body, Client, Method, Request, StatusCode};
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+/// A server process on an ephemeral port, in its own working directory, killed on drop.
+struct TestServer {
+    child: Child,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    fn start() -> Self {
+        Self::start_with(&[], &[])
+    }
+
+    fn start_with(args: &[&str], envs: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "tts-api-server-test-{}-{}",
+            std::process::id(),
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // the port is free once the listener is dropped, and the server takes it right away
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_tts-api-server"))
+            .args(["--backend", "mock", "--socket-addr", &addr.to_string()])
+            .args(args)
+            .env_remove("API_KEY")
+            .env_remove("LLAMA_LOG")
+            .envs(envs.iter().copied())
+            .current_dir(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = TestServer { child, addr, dir };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(server.addr).is_err() {
+            assert!(Instant::now() < deadline, "the server did not start");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        server
+    }
+
+    /// Store a file as llama-core does, in `archives/{id}/{filename}` under the working directory.
+    fn seed_file(&self, id: &str, filename: &str, content: &str) {
+        let dir = self.dir.join("archives").join(id);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(filename), content).unwrap();
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Send `SIGTERM` to the server.
+    fn terminate(&self) {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Wait for the server to exit, for at most `timeout`.
+    fn wait_for_exit(&mut self, timeout: Duration) -> ExitStatus {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "the server did not exit");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<Body>,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        let mut builder = Request::builder().method(method).uri(self.url(path));
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let request = builder.body(body.into()).unwrap();
+
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, headers, body)
+    }
+
+    async fn get(&self, path: &str) -> (StatusCode, HeaderMap, Bytes) {
+        self.send(Method::GET, path, &[], Body::empty()).await
+    }
+
+    async fn post_json(
+        &self,
+        path: &str,
+        json: serde_json::Value,
+    ) -> (StatusCode, HeaderMap, Bytes) {
+        self.send(
+            Method::POST,
+            path,
+            &[("content-type", "application/json")],
+            json.to_string(),
+        )
+        .await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+    headers
+        .get(name)
+        .unwrap_or_else(|| panic!("missing `{}` header", name))
+        .to_str()
+        .unwrap()
+}
+
+fn json(body: &Bytes) -> serde_json::Value {
+    serde_json::from_slice(body).unwrap_or_else(|e| {
+        panic!(
+            "invalid JSON body {:?}: {}",
+            String::from_utf8_lossy(body),
+            e
+        )
+    })
+}
+
+fn assert_cors(headers: &HeaderMap) {
+    assert_eq!(header(headers, "access-control-allow-origin"), "*");
+    assert_eq!(header(headers, "access-control-allow-methods"), "*");
+    assert_eq!(header(headers, "access-control-allow-headers"), "*");
+}
+
+/// A fresh directory for configuration files, apart from the working directory of the server.
+/// Check the response to a request for the file `id` that passed authentication: the file object
+/// in builds with piper, where llama-core serves the files, and `404 Not Found` otherwise.
+fn assert_file_served(status: StatusCode, body: &Bytes, id: &str) {
+    if cfg!(feature = "piper") {
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(body)["id"], id);
+    } else {
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json(body)["error"]["code"], "not_found");
+    }
+}
+
+fn config_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tts-api-server-config-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn speech_returns_wav() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "Hello world.",
+                "voice": "alloy",
+                "response_format": "wav",
+            }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "audio/wav");
+    assert_eq!(
+        header(&headers, "content-disposition"),
+        "attachment; filename=audio.wav"
+    );
+    assert_cors(&headers);
+    assert_eq!(&body[0..4], b"RIFF");
+    assert_eq!(&body[8..12], b"WAVE");
+    // 12 characters of 10ms at 22050Hz, 16-bit mono, after the 44-byte header
+    assert_eq!(body.len(), 44 + 12 * 220 * 2);
+}
+
+#[tokio::test]
+async fn speech_is_deterministic() {
+    let server = TestServer::start();
+    let request = serde_json::json!({
+        "model": "mock",
+        "input": "Same text.",
+        "voice": "echo",
+        "response_format": "pcm",
+    });
+
+    let (_, _, first) = server.post_json("/v1/audio/speech", request.clone()).await;
+    let (_, _, second) = server.post_json("/v1/audio/speech", request).await;
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+
+    let (_, _, other_voice) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "Same text.",
+                "voice": "nova",
+                "response_format": "pcm",
+            }),
+        )
+        .await;
+    assert_ne!(first, other_voice);
+}
+
+#[tokio::test]
+async fn speech_returns_pcm() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hello.", "response_format": "pcm" }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "audio/pcm");
+    assert!(!body.is_empty());
+    assert_eq!(body.len() % 2, 0);
+}
+
+#[cfg(feature = "mp3")]
+#[tokio::test]
+async fn speech_defaults_to_mp3() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hello." }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "audio/mpeg");
+    assert!(!body.is_empty());
+}
+
+#[tokio::test]
+async fn speech_streams_chunks() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "First sentence. Second sentence.",
+                "response_format": "wav",
+                "stream": true,
+            }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "audio/wav");
+    assert_eq!(header(&headers, "transfer-encoding"), "chunked");
+    assert_eq!(&body[0..4], b"RIFF");
+    assert_eq!(
+        body.len(),
+        44 + "First sentence.Second sentence.".len() * 220 * 2
+    );
+}
+
+#[tokio::test]
+async fn speech_streams_sse() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "First sentence. Second sentence.",
+                "response_format": "pcm",
+                "stream_format": "sse",
+            }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "text/event-stream");
+
+    let events: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+        .unwrap()
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .map(|event| serde_json::from_str(event.strip_prefix("data: ").unwrap()).unwrap())
+        .collect();
+    assert_eq!(events.len(), 3);
+    for event in &events[..2] {
+        assert_eq!(event["type"], "speech.audio.delta");
+        assert!(!event["audio"].as_str().unwrap().is_empty());
+    }
+    assert_eq!(events[2]["type"], "speech.audio.done");
+
+    // the pcm deltas join into whole 16-bit samples
+    let audio = joined_deltas(&body);
+    assert!(!audio.is_empty());
+    assert_eq!(audio.len() % 2, 0);
+}
+
+/// The audio of the `speech.audio.delta` events of a server-sent events body, joined.
+fn joined_deltas(body: &Bytes) -> Vec<u8> {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    String::from_utf8(body.to_vec())
+        .unwrap()
+        .split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .map(|event| serde_json::from_str::<serde_json::Value>(event).unwrap())
+        .filter(|event| event["type"] == "speech.audio.delta")
+        .flat_map(|event| {
+            BASE64_STANDARD
+                .decode(event["audio"].as_str().unwrap())
+                .unwrap()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn speech_sse_rejects_formats_that_cannot_be_joined() {
+    let server = TestServer::start();
+
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "First sentence. Second sentence.",
+                "response_format": "wav",
+                "stream_format": "sse",
+            }),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = json(&body);
+    assert_eq!(error["error"]["code"], "invalid_value");
+    assert_eq!(error["error"]["param"], "response_format");
+}
+
+#[cfg(feature = "mp3")]
+#[tokio::test]
+async fn speech_streams_mp3_sse() {
+    let server = TestServer::start();
+
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "First sentence. Second sentence. Third sentence.",
+                "response_format": "mp3",
+                "stream_format": "sse",
+            }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the joined deltas are one run of MPEG layer III frames
+    let audio = joined_deltas(&body);
+    let mut pos = 0;
+    let mut frames = 0;
+    while pos < audio.len() {
+        let header = &audio[pos..pos + 4];
+        assert!(
+            header[0] == 0xff && header[1] & 0xe0 == 0xe0,
+            "no frame at {}",
+            pos
+        );
+        assert_eq!((header[1] >> 1) & 3, 1, "not layer III at {}", pos);
+
+        let mpeg1 = (header[1] >> 3) & 3 == 3;
+        let bitrates: [u32; 15] = match mpeg1 {
+            true => [
+                0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+            ],
+            false => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        };
+        let sample_rates: [u32; 3] = match (header[1] >> 3) & 3 {
+            3 => [44100, 48000, 32000],
+            2 => [22050, 24000, 16000],
+            _ => [11025, 12000, 8000],
+        };
+        let bitrate = bitrates[(header[2] >> 4) as usize] * 1000;
+        let sample_rate = sample_rates[((header[2] >> 2) & 3) as usize];
+        let padding = ((header[2] >> 1) & 1) as u32;
+        // a frame of 1152 samples, or 576 before MPEG-1, in bytes
+        let factor = if mpeg1 { 144 } else { 72 };
+
+        pos += (factor * bitrate / sample_rate + padding) as usize;
+        frames += 1;
+    }
+    assert_eq!(pos, audio.len());
+    assert!(frames >= 3);
+}
+
+#[tokio::test]
+async fn speech_rejects_invalid_requests() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server
+        .send(Method::POST, "/v1/audio/speech", &[], "not json")
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_cors(&headers);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "invalid_request_error");
+    assert_eq!(error["error"]["code"], "invalid_request");
+    assert!(error["error"]["param"].is_null());
+
+    // a missing field cannot be parsed either
+    let (status, _, _) = server
+        .post_json("/v1/audio/speech", serde_json::json!({ "model": "mock" }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hello.", "response_format": "ogg" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = json(&body);
+    assert_eq!(error["error"]["code"], "invalid_value");
+    assert_eq!(error["error"]["param"], "response_format");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Unsupported response format"));
+
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hello.", "response_format": "wav", "stream_format": "chunks" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(json(&body)["error"]["param"], "stream_format");
+
+    // no backend serves the model
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "tts-1", "input": "Hello.", "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = json(&body);
+    assert_eq!(error["error"]["param"], "model");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Available models: mock"));
+
+    let (status, headers, body) = server.get("/v1/audio/speech").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header(&headers, "allow"), "POST");
+    assert_eq!(json(&body)["error"]["code"], "method_not_allowed");
+}
+
+#[tokio::test]
+async fn blank_speech_input_is_unprocessable() {
+    let server = TestServer::start();
+
+    for stream in [false, true] {
+        let (status, headers, body) = server
+            .post_json(
+                "/v1/audio/speech",
+                serde_json::json!({ "model": "mock", "input": " \n ", "response_format": "wav", "stream": stream }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(header(&headers, "content-type"), "application/json");
+        let error = json(&body);
+        assert_eq!(error["error"]["type"], "invalid_request_error");
+        assert_eq!(error["error"]["code"], "unprocessable_entity");
+        assert_eq!(error["error"]["param"], "input");
+    }
+}
+
+#[tokio::test]
+async fn models_lists_the_mock_model() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server.get("/v1/models").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    assert_cors(&headers);
+    let body = json(&body);
+    assert_eq!(body["object"], "list");
+    assert_eq!(body["data"][0]["id"], "mock");
+    assert_eq!(body["data"][0]["object"], "model");
+    assert_eq!(body["data"][0]["owned_by"], "mock");
+    assert!(body["data"][0]["created"].is_u64());
+}
+
+#[tokio::test]
+async fn models_rejects_post() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server.post_json("/v1/models", serde_json::json!({})).await;
+
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header(&headers, "allow"), "GET");
+    assert_cors(&headers);
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "invalid_request_error");
+    assert_eq!(error["error"]["code"], "method_not_allowed");
+}
+
+#[cfg(not(feature = "piper"))]
+#[tokio::test]
+async fn files_are_only_served_with_piper() {
+    let server = TestServer::start();
+
+    for path in [
+        "/v1/files",
+        "/v1/files/file_1234",
+        "/v1/files/file_1234/content",
+    ] {
+        let (status, _, body) = server.get(path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(json(&body)["error"]["code"], "not_found", "{}", path);
+    }
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn files_are_listed() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server.get("/v1/files").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    let list = json(&body);
+    assert_eq!(list["object"], "list");
+    assert_eq!(list["data"].as_array().unwrap().len(), 1);
+    assert_eq!(list["data"][0]["id"], "file_1234");
+    assert_eq!(list["data"][0]["filename"], "hello.txt");
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn files_are_retrieved() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server.get("/v1/files/file_1234").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    let file = json(&body);
+    assert_eq!(file["id"], "file_1234");
+    assert_eq!(file["object"], "file");
+    assert_eq!(file["filename"], "hello.txt");
+    assert_eq!(file["bytes"], 6);
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn file_content_is_retrieved() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server.get("/v1/files/file_1234/content").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    assert_eq!(json(&body), "Hello.");
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn files_are_downloaded() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server.get("/v1/files/download/file_1234").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "text/plain");
+    assert_eq!(
+        header(&headers, "content-disposition"),
+        "attachment; filename=hello.txt"
+    );
+    assert_eq!(&body[..], b"Hello.");
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn files_are_deleted() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server
+        .send(Method::DELETE, "/v1/files/file_1234", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    let deleted = json(&body);
+    assert_eq!(deleted["id"], "file_1234");
+    assert_eq!(deleted["object"], "file");
+    assert_eq!(deleted["deleted"], true);
+
+    let (status, _, _) = server.get("/v1/files/file_1234").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn unsupported_files_requests_are_rejected() {
+    let server = TestServer::start();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = server.get("/v1/files/file_1234/unknown").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    assert_eq!(json(&body)["error"]["code"], "not_found");
+
+    let (status, headers, body) = server
+        .send(Method::POST, "/v1/files", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header(&headers, "allow"), "GET, DELETE");
+    assert_eq!(json(&body)["error"]["code"], "method_not_allowed");
+}
+
+#[cfg(feature = "piper")]
+#[tokio::test]
+async fn unknown_files_are_not_found() {
+    let server = TestServer::start();
+
+    for (method, path) in [
+        (Method::GET, "/v1/files/file_missing"),
+        (Method::GET, "/v1/files/file_missing/content"),
+        (Method::GET, "/v1/files/download/file_missing"),
+        (Method::DELETE, "/v1/files/file_missing"),
+    ] {
+        let (status, headers, body) = server.send(method, path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        assert_eq!(header(&headers, "content-type"), "application/json");
+        let error = json(&body);
+        assert_eq!(error["error"]["type"], "invalid_request_error", "{}", path);
+        assert_eq!(error["error"]["code"], "not_found", "{}", path);
+        assert!(error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("file_missing"));
+    }
+
+    // an id that could leave the archives directory is malformed
+    for (method, path) in [
+        (Method::GET, "/v1/files/download/file%2e%2e"),
+        (Method::DELETE, "/v1/files/file_%20x"),
+    ] {
+        let (status, _, body) = server.send(method, path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        assert_eq!(json(&body)["error"]["code"], "invalid_request", "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn preflight_requests_are_answered() {
+    let server = TestServer::start();
+
+    for path in [
+        "/v1/audio/speech",
+        "/v1/models",
+        "/v1/files",
+        "/v1/files/file_1234",
+    ] {
+        let (status, headers, body) = server
+            .send(
+                Method::OPTIONS,
+                path,
+                &[
+                    ("origin", "https://example.com"),
+                    ("access-control-request-method", "POST"),
+                ],
+                Body::empty(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert_cors(&headers);
+        assert!(body.is_empty(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn unknown_routes_are_not_found() {
+    let server = TestServer::start();
+
+    for path in ["/", "/v2/audio/speech", "/v1/unknown", "/v1/audio/speeches"] {
+        let (status, headers, body) = server.get(path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        assert_cors(&headers);
+        let error = json(&body);
+        assert_eq!(error["error"]["type"], "invalid_request_error", "{}", path);
+        assert_eq!(error["error"]["code"], "not_found", "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn echo_answers() {
+    let server = TestServer::start();
+
+    let (status, _, body) = server.get("/echo").await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"echo test");
+}
+
+#[tokio::test]
+async fn websocket_route_requires_an_upgrade() {
+    let server = TestServer::start();
+
+    let (status, _, _) = server.get("/v1/audio/speech/ws").await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn websocket_route_requires_version_13() {
+    let server = TestServer::start();
+
+    let (status, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/audio/speech/ws",
+            &[
+                ("connection", "Upgrade"),
+                ("upgrade", "websocket"),
+                ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+                ("sec-websocket-version", "8"),
+            ],
+            Body::empty(),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(header(&headers, "sec-websocket-version"), "13");
+}
+
+#[tokio::test]
+async fn websocket_session_synthesizes_sentences() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let server = TestServer::start();
+    let stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    let url = format!("ws://{}/v1/audio/speech/ws", server.addr);
+    let (mut ws, response) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+    assert_eq!(response.status().as_u16(), 101);
+
+    for event in [
+        serde_json::json!({ "type": "config", "model": "mock" }),
+        serde_json::json!({ "type": "text", "text": "Hello there. Bye" }),
+        serde_json::json!({ "type": "close" }),
+    ] {
+        ws.send(Message::Text(event.to_string())).await.unwrap();
+    }
+
+    let mut events = Vec::new();
+    let mut audio = Vec::new();
+    while let Some(message) = ws.next().await {
+        match message.unwrap() {
+            Message::Text(text) => {
+                events.push(serde_json::from_str::<serde_json::Value>(&text).unwrap())
+            }
+            Message::Binary(bytes) => audio.push(bytes),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    let types: Vec<&str> = events
+        .iter()
+        .map(|event| event["type"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        types,
+        [
+            "segment.start",
+            "segment.end",
+            "segment.start",
+            "segment.end",
+            "done"
+        ]
+    );
+    assert_eq!(events[0]["text"], "Hello there.");
+    assert_eq!(events[2]["text"], "Bye");
+    assert_eq!(events[2]["segment"], 2);
+
+    // pcm by default, with 10ms of tone per character
+    assert_eq!(audio.len(), 2);
+    assert_eq!(events[1]["bytes"], audio[0].len());
+    assert_eq!(events[3]["bytes"], audio[1].len());
+    assert!(audio[0].len() > audio[1].len());
+    assert_eq!(audio[0].len() % 2, 0);
+}
+
+#[tokio::test]
+async fn api_key_is_checked() {
+    let server = TestServer::start_with(&[], &[("API_KEY", "secret-key")]);
+    let request = serde_json::json!({ "model": "mock", "input": "Hi.", "response_format": "wav" });
+
+    let (status, _, _) = server
+        .send(
+            Method::POST,
+            "/v1/audio/speech",
+            &[("authorization", "Bearer secret-key")],
+            request.to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, headers, body) = server
+        .send(
+            Method::POST,
+            "/v1/audio/speech",
+            &[("authorization", "Bearer wrong-key")],
+            request.to_string(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_cors(&headers);
+    assert_eq!(header(&headers, "content-type"), "application/json");
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "authentication_error");
+    assert_eq!(error["error"]["code"], "invalid_api_key");
+    assert_eq!(error["error"]["message"], "Invalid API key.");
+
+    let (status, _, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[("authorization", "Bearer wrong-key")],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn api_key_is_required_without_a_header() {
+    let server = TestServer::start_with(&[], &[("API_KEY", "secret-key")]);
+
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hi.", "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(String::from_utf8_lossy(&body).contains("Missing API key"));
+
+    for path in ["/v1/models", "/v1/files", "/v1/unknown"] {
+        let (status, _, _) = server.get(path).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn api_key_rejects_malformed_schemes() {
+    let server = TestServer::start_with(&[], &[("API_KEY", "secret-key")]);
+
+    for auth_header in [
+        "secret-key",
+        "Basic secret-key",
+        "Bearer",
+        "Bearer ",
+        "Token secret-key",
+    ] {
+        let (status, _, body) = server
+            .send(
+                Method::GET,
+                "/v1/models",
+                &[("authorization", auth_header)],
+                Body::empty(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", auth_header);
+        assert!(
+            String::from_utf8_lossy(&body).contains("Malformed authorization header"),
+            "{}",
+            auth_header
+        );
+    }
+
+    // the scheme is case-insensitive
+    let (status, _, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[("authorization", "bearer secret-key")],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn public_routes_and_preflight_skip_the_api_key() {
+    let server = TestServer::start_with(&[], &[("API_KEY", "secret-key")]);
+
+    let (status, _, body) = server.get("/echo").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"echo test");
+
+    let (status, headers, _) = server
+        .send(Method::OPTIONS, "/v1/audio/speech", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_cors(&headers);
+}
+
+#[tokio::test]
+async fn public_routes_are_configurable() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(
+        &config_file,
+        "[auth]\napi_key = \"secret-key\"\npublic_routes = [\"/v1/models\", \"/v1/files/*\"]\n",
+    )
+    .unwrap();
+
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, _, body) = server.get("/v1/models").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json(&body)["object"], "list");
+    let (status, _, body) = server.get("/v1/files/file_1234").await;
+    assert_file_served(status, &body, "file_1234");
+
+    // the configured routes replace the defaults
+    let (status, _, _) = server.get("/echo").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = server.get("/v1/files").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+const API_KEYS_CONFIG: &str = r#"
+[auth]
+keys_file = "keys.yaml"
+
+[[auth.keys]]
+name = "speech"
+key = "speech-key"
+routes = ["POST /v1/audio/speech"]
+voices = ["mock", "alloy"]
+daily_char_quota = 20
+
+[[auth.keys]]
+name = "admin"
+key = "admin-key"
+"#;
+
+const API_KEYS_FILE: &str = r#"
+keys:
+  - name: files-reader
+    # sha256 of `reader-key`
+    key_sha256: ec4408df15da46b328f6f3246fa723d0aa6cb0f0a0dd9c4626080ab1b02aa3b2
+    routes: ["GET /v1/files*", "/v1/models"]
+  - name: former-team
+    key: revoked-key
+    revoked: true
+"#;
+
+/// Start a server with the keys of `API_KEYS_CONFIG` and `API_KEYS_FILE`.
+fn start_with_api_keys() -> (TestServer, PathBuf) {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, API_KEYS_CONFIG).unwrap();
+    std::fs::write(dir.join("keys.yaml"), API_KEYS_FILE).unwrap();
+
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    (server, dir)
+}
+
+async fn send_with_key(
+    server: &TestServer,
+    method: Method,
+    path: &str,
+    key: &str,
+    body: impl Into<Body>,
+) -> (StatusCode, HeaderMap, Bytes) {
+    let auth_header = format!("Bearer {}", key);
+    server
+        .send(method, path, &[("authorization", &auth_header)], body)
+        .await
+}
+
+#[tokio::test]
+async fn api_keys_are_limited_to_their_routes() {
+    let (server, dir) = start_with_api_keys();
+    server.seed_file("file_1234", "hello.txt", "Hello.");
+
+    let (status, headers, body) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "speech-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_cors(&headers);
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "permission_error");
+    assert_eq!(error["error"]["code"], "route_not_allowed");
+
+    // the reader may list the models and read files, but not delete them
+    let (status, _, body) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "reader-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(json(&body)["data"][0]["id"], "mock");
+    let (status, _, body) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/files/file_1234",
+        "reader-key",
+        Body::empty(),
+    )
+    .await;
+    assert_file_served(status, &body, "file_1234");
+    let (status, _, body) = send_with_key(
+        &server,
+        Method::DELETE,
+        "/v1/files/file_1234",
+        "reader-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(json(&body)["error"]["code"], "route_not_allowed");
+
+    // keys without routes may use every route
+    let (status, _, body) = send_with_key(
+        &server,
+        Method::DELETE,
+        "/v1/files/file_1234",
+        "admin-key",
+        Body::empty(),
+    )
+    .await;
+    assert_file_served(status, &body, "file_1234");
+    if cfg!(feature = "piper") {
+        assert_eq!(json(&body)["deleted"], true);
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn api_keys_are_limited_to_their_voices() {
+    let (server, dir) = start_with_api_keys();
+
+    for (request, expected) in [
+        (
+            serde_json::json!({ "model": "mock", "input": "Hi.", "response_format": "wav" }),
+            StatusCode::OK,
+        ),
+        (
+            serde_json::json!({ "model": "mock", "input": "Hi.", "voice": "alloy", "response_format": "wav" }),
+            StatusCode::OK,
+        ),
+        (
+            serde_json::json!({ "model": "mock", "input": "Hi.", "voice": "echo", "response_format": "wav" }),
+            StatusCode::FORBIDDEN,
+        ),
+    ] {
+        let (status, _, body) = send_with_key(
+            &server,
+            Method::POST,
+            "/v1/audio/speech",
+            "speech-key",
+            request.to_string(),
+        )
+        .await;
+        assert_eq!(status, expected, "{}", request);
+        if status == StatusCode::FORBIDDEN {
+            assert_eq!(json(&body)["error"]["code"], "voice_not_allowed");
+        }
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn api_keys_have_daily_character_quotas() {
+    let (server, dir) = start_with_api_keys();
+    // 12 characters
+    let request =
+        serde_json::json!({ "model": "mock", "input": "Hello world.", "response_format": "wav" });
+
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "speech-key",
+        request.to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, headers, body) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "speech-key",
+        request.to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = header(&headers, "retry-after").parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 24 * 60 * 60);
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "insufficient_quota");
+    assert_eq!(error["error"]["code"], "daily_quota_exceeded");
+
+    // a shorter input still fits in the quota, and other keys have their own
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "speech-key",
+        serde_json::json!({ "model": "mock", "input": "Hi.", "response_format": "wav" })
+            .to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "admin-key",
+        request.to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn failed_requests_do_not_use_the_quota() {
+    let (server, dir) = start_with_api_keys();
+
+    // 15 characters of whitespace have nothing to synthesize
+    let empty = serde_json::json!({ "model": "mock", "input": " ".repeat(15), "response_format": "wav", "stream": true });
+    for _ in 0..2 {
+        let (status, _, _) = send_with_key(
+            &server,
+            Method::POST,
+            "/v1/audio/speech",
+            "speech-key",
+            empty.to_string(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    // a voice outside the scope is rejected before it is charged
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "speech-key",
+        serde_json::json!({ "model": "mock", "input": "Hello world.", "voice": "echo", "response_format": "wav" })
+            .to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // 18 characters of the 20 of the quota
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::POST,
+        "/v1/audio/speech",
+        "speech-key",
+        serde_json::json!({ "model": "mock", "input": "Hello there world.", "response_format": "wav" })
+            .to_string(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn revoked_api_keys_are_rejected() {
+    let (server, dir) = start_with_api_keys();
+
+    let (status, _, body) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "revoked-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let error = json(&body);
+    assert_eq!(error["error"]["code"], "revoked_api_key");
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("former-team"));
+
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "reader-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn api_keys_can_be_configured_by_digest() {
+    let dir = config_dir();
+    let config_file = dir.join("config.yaml");
+    std::fs::write(
+        &config_file,
+        // sha256 of `secret-key`, in upper case as some tools print it
+        "auth:\n  api_key_sha256: 85DBE15D75EF9308C7AE0F33C7A324CC6F4BF519A2ED2F3027BD33C140A4F9AA\n",
+    )
+    .unwrap();
+
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "secret-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // the digest itself is not a key
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "85dbe15d75ef9308c7ae0f33c7a324cc6f4bf519a2ed2f3027bd33c140a4f9aa",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Start a server with small request limits.
+fn start_with_limits() -> (TestServer, PathBuf) {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(
+        &config_file,
+        "[limits]\nmax_body_bytes = 256\nmax_input_chars = 50\nmax_audio_seconds = 2\n",
+    )
+    .unwrap();
+
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    (server, dir)
+}
+
+#[tokio::test]
+async fn request_bodies_are_limited() {
+    let (server, dir) = start_with_limits();
+    let request = serde_json::json!({
+        "model": "mock",
+        "input": "Hi.",
+        "response_format": "wav",
+        "padding": "x".repeat(300),
+    })
+    .to_string();
+
+    let (status, headers, body) = server
+        .send(Method::POST, "/v1/audio/speech", &[], request.clone())
+        .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_cors(&headers);
+    assert_eq!(json(&body)["error"]["code"], "payload_too_large");
+
+    // without a content length the body is cut off while it is read
+    let chunks: Vec<Result<String, std::io::Error>> = request
+        .as_bytes()
+        .chunks(64)
+        .map(|chunk| Ok(String::from_utf8(chunk.to_vec()).unwrap()))
+        .collect();
+    let (status, _, _) = server
+        .send(
+            Method::POST,
+            "/v1/audio/speech",
+            &[],
+            Body::wrap_stream(futures_util::stream::iter(chunks)),
+        )
+        .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn speech_inputs_are_limited() {
+    let (server, dir) = start_with_limits();
+
+    // 51 characters
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "a".repeat(51), "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = json(&body);
+    assert_eq!(error["error"]["code"], "string_above_max_length");
+    assert_eq!(error["error"]["param"], "input");
+
+    // 40 characters take about 2.7 seconds to read
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "a".repeat(40), "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(json(&body)["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("seconds"));
+
+    let (status, _, _) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "a".repeat(30), "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn syntheses_wait_in_a_bounded_queue() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(
+        &config_file,
+        "[limits]\nmax_input_chars = 0\nmax_audio_seconds = 0\n\n[queue]\nmax_concurrent = 1\nmax_queued = 1\ntimeout_secs = 1\n",
+    )
+    .unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    // a long stream that is not read holds the only synthesis slot
+    let input = format!("{}. ", "a".repeat(98)).repeat(2000);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(server.url("/v1/audio/speech"))
+        .body(Body::from(
+            serde_json::json!({ "model": "mock", "input": input, "response_format": "wav", "stream": true })
+                .to_string(),
+        ))
+        .unwrap();
+    let stream = Client::new().request(request).await.unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    let speech = serde_json::json!({ "model": "mock", "input": "Hi.", "response_format": "wav" });
+
+    // the next request waits in the queue
+    let queued = tokio::spawn({
+        let url = server.url("/v1/audio/speech");
+        let speech = speech.clone();
+        async move {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .body(Body::from(speech.to_string()))
+                .unwrap();
+            let response = Client::new().request(request).await.unwrap();
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, headers, body)
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let (status, headers, body) = server.get("/queue").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "x-queue-depth"), "1");
+    assert_eq!(
+        json(&body),
+        serde_json::json!({ "in_flight": 1, "max_concurrent": 1, "queued": 1, "max_queued": 1 })
+    );
+
+    // the queue is full
+    let (status, headers, body) = server.post_json("/v1/audio/speech", speech.clone()).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_cors(&headers);
+    assert_eq!(header(&headers, "retry-after"), "1");
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "server_error");
+    assert_eq!(error["error"]["code"], "service_unavailable");
+
+    // the queued request gives up after the timeout
+    let (status, headers, body) = queued.await.unwrap();
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(header(&headers, "retry-after"), "1");
+    assert!(json(&body)["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("within 1 seconds"));
+
+    // closing the stream frees the slot
+    drop(stream);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let (_, _, body) = server.get("/queue").await;
+        if json(&body)["in_flight"] == 0 {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the synthesis slot was not freed"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let (status, _, _) = server.post_json("/v1/audio/speech", speech).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+const RATE_LIMIT_CONFIG: &str = r#"
+[rate_limit]
+requests_per_minute = 3
+chars_per_minute = 20
+
+[[auth.keys]]
+name = "first"
+key = "first-key"
+
+[[auth.keys]]
+name = "second"
+key = "second-key"
+"#;
+
+#[tokio::test]
+async fn requests_are_rate_limited_per_api_key() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, RATE_LIMIT_CONFIG).unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    for remaining in ["2", "1", "0"] {
+        let (status, headers, _) = send_with_key(
+            &server,
+            Method::GET,
+            "/v1/models",
+            "first-key",
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit-requests"), "3");
+        assert_eq!(
+            header(&headers, "x-ratelimit-remaining-requests"),
+            remaining
+        );
+        assert_eq!(header(&headers, "x-ratelimit-limit-characters"), "20");
+    }
+
+    let (status, headers, body) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "first-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_cors(&headers);
+    assert_eq!(header(&headers, "retry-after"), "20");
+    assert_eq!(header(&headers, "x-ratelimit-remaining-requests"), "0");
+    let error = json(&body);
+    assert_eq!(error["error"]["type"], "rate_limit_error");
+    assert_eq!(error["error"]["code"], "rate_limit_exceeded");
+
+    // each key has its own buckets
+    let (status, _, _) = send_with_key(
+        &server,
+        Method::GET,
+        "/v1/models",
+        "second-key",
+        Body::empty(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // routes outside of the API are not limited
+    let (status, headers, _) = server.get("/echo").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get("x-ratelimit-limit-requests").is_none());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn synthesized_characters_are_rate_limited_per_client() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, "[rate_limit]\nchars_per_minute = 20\n").unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    let speech = |input: &str| serde_json::json!({ "model": "mock", "input": input, "response_format": "wav" });
+
+    let (status, headers, _) = server
+        .post_json("/v1/audio/speech", speech(&"a".repeat(15)))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "x-ratelimit-remaining-characters"), "5");
+    assert!(headers.get("x-ratelimit-limit-requests").is_none());
+
+    // 5 more characters take 15 seconds to refill
+    let (status, headers, body) = server
+        .post_json("/v1/audio/speech", speech(&"a".repeat(10)))
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&headers, "retry-after"), "15");
+    assert_eq!(json(&body)["error"]["code"], "rate_limit_exceeded");
+
+    // an input over the limit never fits
+    let (status, headers, body) = server
+        .post_json("/v1/audio/speech", speech(&"a".repeat(21)))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(headers.get("retry-after").is_none());
+    assert_eq!(json(&body)["error"]["param"], "input");
+
+    let (status, _, _) = server.post_json("/v1/audio/speech", speech("Hi.")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn unsent_stream_sentences_are_refunded() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(
+        &config_file,
+        "[rate_limit]\nchars_per_minute = 300000\n\n[limits]\nmax_input_chars = 0\nmax_audio_seconds = 0\n",
+    )
+    .unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    // 200000 characters are charged when the stream starts
+    let input = format!("{}. ", "a".repeat(98)).repeat(2000);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(server.url("/v1/audio/speech"))
+        .body(Body::from(
+            serde_json::json!({ "model": "mock", "input": input, "response_format": "pcm", "stream": true })
+                .to_string(),
+        ))
+        .unwrap();
+    let stream = Client::new().request(request).await.unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+    let remaining: u64 = header(stream.headers(), "x-ratelimit-remaining-characters")
+        .parse()
+        .unwrap();
+    assert!(remaining < 110000);
+
+    // the sentences left when the client hangs up are given back
+    drop(stream);
+    let speech = serde_json::json!({ "model": "mock", "input": "a", "response_format": "wav" });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (status, headers, _) = server.post_json("/v1/audio/speech", speech.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let remaining: u64 = header(&headers, "x-ratelimit-remaining-characters")
+            .parse()
+            .unwrap();
+        if remaining > 250000 {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the unsent characters were not refunded"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn metrics_are_served_to_prometheus() {
+    let server = TestServer::start();
+
+    let (status, _, _) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({ "model": "mock", "input": "Hello there.", "response_format": "wav" }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = server.get("/nowhere").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, headers, body) = server.get("/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(header(&headers, "content-type").starts_with("text/plain; version=0.0.4"));
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    let lines: Vec<&str> = metrics.lines().collect();
+
+    for line in [
+        "# TYPE tts_http_requests_total counter",
+        "tts_http_requests_total{route=\"/v1/audio/speech\",status=\"200\"} 1",
+        "tts_http_requests_total{route=\"other\",status=\"404\"} 1",
+        "tts_http_request_duration_seconds_count{route=\"/v1/audio/speech\"} 1",
+        "tts_http_request_duration_seconds_bucket{route=\"/v1/audio/speech\",le=\"+Inf\"} 1",
+        "tts_synthesized_characters_total{voice=\"mock\"} 12",
+        "tts_real_time_factor_count{voice=\"mock\"} 1",
+        "tts_queue_in_flight 0",
+        "tts_queue_depth 0",
+    ] {
+        assert!(lines.contains(&line), "missing `{}` in\n{}", line, metrics);
+    }
+
+    // 220 samples per character at 22050 Hz
+    let audio_seconds: f64 = lines
+        .iter()
+        .find_map(|line| line.strip_prefix("tts_audio_seconds_total{voice=\"mock\"} "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((audio_seconds - 12.0 * 220.0 / 22050.0).abs() < 1e-9);
+
+    let (status, _, _) = server
+        .send(Method::POST, "/metrics", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn health_ready_and_version_are_reported() {
+    let server = TestServer::start();
+
+    let (status, headers, body) = server.get("/health").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_cors(&headers);
+    assert_eq!(json(&body), serde_json::json!({ "status": "ok" }));
+
+    let (status, _, body) = server.get("/ready").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        json(&body),
+        serde_json::json!({
+            "status": "ready",
+            "backends": [{
+                "name": "mock",
+                "ready": true,
+                "voices": [{ "name": "mock", "ready": true }],
+            }],
+        })
+    );
+
+    let (status, _, body) = server.get("/version").await;
+    assert_eq!(status, StatusCode::OK);
+    let version = json(&body);
+    assert_eq!(version["name"], "tts-api-server");
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert!(version["features"].is_array());
+    assert_eq!(
+        version["backends"],
+        serde_json::json!([{ "name": "mock", "models": [{ "id": "mock" }] }])
+    );
+
+    let (status, _, _) = server
+        .send(Method::POST, "/ready", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn probes_are_public_by_default() {
+    let server = TestServer::start_with(&[], &[("API_KEY", "secret")]);
+
+    for path in ["/health", "/ready", "/version"] {
+        let (status, _, _) = server.get(path).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+    }
+
+    let (status, _, _) = server.get("/v1/models").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn request_ids_are_echoed_or_generated() {
+    let server = TestServer::start();
+
+    let (_, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[("x-request-id", "client-id-42")],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(header(&headers, "x-request-id"), "client-id-42");
+
+    let (_, first, _) = server.get("/v1/models").await;
+    let (_, second, _) = server.get("/nowhere").await;
+    let first = header(&first, "x-request-id");
+    assert!(uuid::Uuid::parse_str(first).is_ok());
+    assert_ne!(first, header(&second, "x-request-id"));
+
+    // unusable ids are replaced
+    let long_id = "x".repeat(200);
+    let (_, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[("x-request-id", long_id.as_str())],
+            Body::empty(),
+        )
+        .await;
+    assert!(uuid::Uuid::parse_str(header(&headers, "x-request-id")).is_ok());
+}
+
+#[tokio::test]
+async fn speech_timings_are_returned_when_enabled() {
+    let speech = |stream: bool| {
+        serde_json::json!({
+            "model": "mock",
+            "input": "Hello there.",
+            "response_format": "wav",
+            "stream": stream,
+        })
+    };
+
+    let server = TestServer::start();
+    let (_, headers, _) = server.post_json("/v1/audio/speech", speech(false)).await;
+    assert!(headers.get("server-timing").is_none());
+    drop(server);
+
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, "[server]\ntiming_headers = true\n").unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    let (status, headers, _) = server.post_json("/v1/audio/speech", speech(false)).await;
+    assert_eq!(status, StatusCode::OK);
+    let stages: Vec<&str> = header(&headers, "server-timing")
+        .split(", ")
+        .map(|stage| stage.split_once(";dur=").unwrap().0)
+        .collect();
+    assert_eq!(stages, ["queue", "synthesis", "encode"]);
+    assert_eq!(header(&headers, "x-tts-audio-seconds"), "0.12");
+    assert!(header(&headers, "x-tts-real-time-factor")
+        .parse::<f64>()
+        .is_ok());
+
+    // a stream starts before the audio is synthesized
+    let (status, headers, _) = server.post_json("/v1/audio/speech", speech(true)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(header(&headers, "server-timing").starts_with("queue;dur="));
+    assert!(header(&headers, "server-timing").contains("text;dur="));
+    assert!(headers.get("x-tts-audio-seconds").is_none());
+
+    // server-sent events close with all the timings
+    let (status, _, body) = server
+        .post_json(
+            "/v1/audio/speech",
+            serde_json::json!({
+                "model": "mock",
+                "input": "Hello there.",
+                "response_format": "pcm",
+                "stream_format": "sse",
+            }),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let done: serde_json::Value = String::from_utf8(body.to_vec())
+        .unwrap()
+        .split("\n\n")
+        .filter(|event| !event.is_empty())
+        .last()
+        .map(|event| serde_json::from_str(event.strip_prefix("data: ").unwrap()).unwrap())
+        .unwrap();
+    assert_eq!(done["type"], "speech.audio.done");
+    for stage in ["queue_ms", "text_ms", "synthesis_ms", "encode_ms", "rtf"] {
+        assert!(done["timings"][stage].is_number(), "missing `{}`", stage);
+    }
+    assert_eq!(done["timings"]["audio_seconds"], 0.12);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Start a server shutting down within `shutdown_timeout_secs`, and a speech stream of `sentences`
+/// sentences that is not read yet.
+async fn start_with_stream(
+    shutdown_timeout_secs: u64,
+    sentences: usize,
+) -> (TestServer, PathBuf, hyper::Response<Body>) {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(
+        &config_file,
+        format!(
+            "[server]\nshutdown_timeout_secs = {}\n\n[limits]\nmax_input_chars = 0\nmax_audio_seconds = 0\n",
+            shutdown_timeout_secs
+        ),
+    )
+    .unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    let input = format!("{}. ", "a".repeat(98)).repeat(sentences);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(server.url("/v1/audio/speech"))
+        .body(Body::from(
+            serde_json::json!({ "model": "mock", "input": input, "response_format": "pcm", "stream": true })
+                .to_string(),
+        ))
+        .unwrap();
+    let stream = Client::new().request(request).await.unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    (server, dir, stream)
+}
+
+#[tokio::test]
+async fn shutdown_drains_requests_in_flight() {
+    let (mut server, dir, stream) = start_with_stream(10, 100).await;
+
+    server.terminate();
+
+    // no new connection is accepted
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(server.addr).is_ok() {
+        assert!(
+            Instant::now() < deadline,
+            "the server still accepts connections"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // the stream in flight is sent to the end
+    let body = hyper::body::to_bytes(stream.into_body()).await.unwrap();
+    assert!(!body.is_empty());
+    assert_eq!(body.len() % 2, 0);
+
+    let status = server.wait_for_exit(Duration::from_secs(5));
+    assert!(status.success());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn shutdown_aborts_requests_after_the_timeout() {
+    // a long stream that is not read never finishes
+    let (mut server, dir, stream) = start_with_stream(1, 2000).await;
+
+    let start = Instant::now();
+    server.terminate();
+    let status = server.wait_for_exit(Duration::from_secs(10));
+    assert!(status.success());
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    // the stream is cut off
+    assert!(hyper::body::to_bytes(stream.into_body()).await.is_err());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn shutdown_route_drains_the_server() {
+    // the route is not served by default
+    let server = TestServer::start();
+    let (status, _, _) = server
+        .send(Method::POST, "/shutdown", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    drop(server);
+
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, "[server]\nshutdown_route = true\n").unwrap();
+    let mut server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    let (status, _, _) = server.get("/shutdown").await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+    let (status, _, body) = server
+        .send(Method::POST, "/shutdown", &[], Body::empty())
+        .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["status"], "draining");
+    assert_eq!(body["in_flight"], 0);
+
+    let status = server.wait_for_exit(Duration::from_secs(5));
+    assert!(status.success());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+const CORS_CONFIG: &str = r#"
+[cors]
+allowed_origins = ["https://app.example.com"]
+allowed_methods = ["GET", "POST"]
+allowed_headers = ["authorization", "content-type"]
+allow_credentials = true
+max_age_secs = 600
+
+[[auth.keys]]
+name = "app"
+key = "app-key"
+"#;
+
+#[tokio::test]
+async fn cors_policy_is_configurable() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, CORS_CONFIG).unwrap();
+    let server = TestServer::start_with(&["--config-file", config_file.to_str().unwrap()], &[]);
+
+    // preflight requests are answered for every route, without an API key
+    for path in ["/v1/audio/speech", "/v1/models", "/health", "/unknown"] {
+        let (status, headers, body) = server
+            .send(
+                Method::OPTIONS,
+                path,
+                &[
+                    ("origin", "https://app.example.com"),
+                    ("access-control-request-method", "POST"),
+                    ("access-control-request-headers", "authorization"),
+                ],
+                Body::empty(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+        assert!(body.is_empty(), "{}", path);
+        assert_eq!(
+            header(&headers, "access-control-allow-origin"),
+            "https://app.example.com"
+        );
+        assert_eq!(header(&headers, "access-control-allow-credentials"), "true");
+        assert_eq!(
+            header(&headers, "access-control-allow-methods"),
+            "GET, POST"
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-headers"),
+            "authorization, content-type"
+        );
+        assert_eq!(header(&headers, "access-control-max-age"), "600");
+        assert_eq!(header(&headers, "vary"), "Origin");
+    }
+
+    // other origins get no CORS headers
+    let (status, headers, _) = server
+        .send(
+            Method::OPTIONS,
+            "/v1/audio/speech",
+            &[
+                ("origin", "https://evil.example.com"),
+                ("access-control-request-method", "POST"),
+            ],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get("access-control-allow-origin").is_none());
+    assert!(headers.get("access-control-allow-methods").is_none());
+
+    let (status, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[
+                ("origin", "https://evil.example.com"),
+                ("authorization", "Bearer app-key"),
+            ],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get("access-control-allow-origin").is_none());
+    assert_eq!(header(&headers, "vary"), "Origin");
+
+    // allowed origins can read responses and errors
+    let (status, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[
+                ("origin", "https://app.example.com"),
+                ("authorization", "Bearer app-key"),
+            ],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        header(&headers, "access-control-allow-origin"),
+        "https://app.example.com"
+    );
+    assert_eq!(header(&headers, "access-control-allow-credentials"), "true");
+
+    let (status, headers, _) = server
+        .send(
+            Method::GET,
+            "/v1/models",
+            &[("origin", "https://app.example.com")],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        header(&headers, "access-control-allow-origin"),
+        "https://app.example.com"
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn preflight_requests_echo_the_requested_headers_by_default() {
+    let server = TestServer::start();
+
+    let (status, headers, _) = server
+        .send(
+            Method::OPTIONS,
+            "/v1/audio/speech",
+            &[
+                ("origin", "https://example.com"),
+                ("access-control-request-method", "POST"),
+                (
+                    "access-control-request-headers",
+                    "authorization, content-type",
+                ),
+            ],
+            Body::empty(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(header(&headers, "access-control-allow-origin"), "*");
+    assert_eq!(header(&headers, "access-control-allow-methods"), "*");
+    assert_eq!(
+        header(&headers, "access-control-allow-headers"),
+        "authorization, content-type"
+    );
+    assert!(headers.get("access-control-allow-credentials").is_none());
+    assert!(headers.get("vary").is_none());
+}
+
+#[test]
+fn cors_credentials_need_listed_origins() {
+    let dir = config_dir();
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, "[cors]\nallow_credentials = true\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_tts-api-server"))
+        .args([
+            "--backend",
+            "mock",
+            "--config-file",
+            config_file.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("cors.allow_credentials"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}